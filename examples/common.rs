@@ -1,10 +1,13 @@
-/**
+/*
 © 2020 PERLUR Group
 
 SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 
 */
 
+/* Shared by all examples, none of which uses every setting. */
+#![allow(dead_code)]
+
 use simple_logger::SimpleLogger;
 use std::path::PathBuf;
 
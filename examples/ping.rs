@@ -1,59 +1,59 @@
-/**
+/*
 © 2020 PERLUR Group
 
 SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 
 */
 
-use cardano_ouroboros_network::mux;
+use cardano_ouroboros_network::{
+    mux::{self, RoundTrip},
+    Error,
+};
 use std::{
     env,
     time::Duration,
 };
 use log::{info, error};
-use futures::{
-    executor::block_on,
-    future::join_all,
-};
+use futures::future::join_all;
 
 mod common;
 
-async fn ping(host: &String, port: u16, magic: u32) -> Result<(Duration, Duration), String>{
+async fn ping(host: &str, port: u16, magic: u32) -> Result<(Duration, Duration, RoundTrip), Error> {
     info!("Pinging host {} port {} magic {}.", host, port, magic);
-    let channel = match mux::tcp::connect(&host, port).await {
-        Ok(channel) => channel,
-        Err(_) => { return Err("Could not connect.".to_string()) }
-    };
+    let channel = mux::tcp::connect(host, port).await?;
     let connect_duration = channel.duration();
-    channel.handshake(magic).await?;
+    let handshake = channel.handshake(magic).await?;
+    info!("Host {} accepted version {}.", host, handshake.version);
     let total_duration = channel.duration();
-    Ok((connect_duration, total_duration))
+    Ok((connect_duration, total_duration, channel.round_trip()))
 }
 
-fn main() {
+/* All hosts are pinged concurrently on a single thread. */
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
     let cfg = common::init();
     let port = cfg.port;
     let magic = cfg.magic;
 
-    block_on(async {
-        let mut args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
 
-        args.remove(0);
+    args.remove(0);
 
-        /* Use configured host by default. */
-        if args.len() == 0 {
-            args = vec![cfg.host.clone()];
-        }
+    /* Use configured host by default. */
+    if args.is_empty() {
+        args = vec![cfg.host.clone()];
+    }
 
-        join_all(args.iter().map(|host| async move {
-            match ping(&host.clone(), port, magic).await {
-                Ok((connect_duration, total_duration)) => {
-                    info!("Ping {}:{} success! : connect_duration: {}, total_duration: {}", &host, port, connect_duration.as_millis(), total_duration.as_millis());
-                }
-                Err(error) => {
-                    error!("Ping {}:{} failed! : {:?}", &host, port, error);
-                }
+    join_all(args.iter().map(|host| async move {
+        match ping(host, port, magic).await {
+            Ok((connect_duration, total_duration, round_trip)) => {
+                info!("Ping {}:{} success! : connect_duration: {}, total_duration: {}, round_trip: {:?}, clock_offset: {:?}",
+                      &host, port, connect_duration.as_millis(), total_duration.as_millis(),
+                      round_trip.min.map(|rtt| rtt.as_millis()), round_trip.clock_offset);
             }
-        })).await;
-    });
+            Err(error) => {
+                error!("Ping {}:{} failed! : {}", &host, port, error);
+            }
+        }
+    })).await;
 }
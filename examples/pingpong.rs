@@ -1,4 +1,4 @@
-/**
+/*
 © 2020 PERLUR Group
 
 SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
@@ -9,16 +9,14 @@ use cardano_ouroboros_network::{
     mux,
     protocols::pingpong,
 };
-use futures::executor::block_on;
 
 mod common;
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let cfg = common::init();
 
-    block_on(async {
-        let channel = mux::tcp::connect("127.0.0.1", cfg.port).await.unwrap();
-        channel.handshake(cfg.magic).await.unwrap();
-        channel.execute(pingpong::PingPongProtocol::new(0x0100)).await.unwrap();
-    });
+    let channel = mux::tcp::connect("127.0.0.1", cfg.port).await.unwrap();
+    channel.handshake(cfg.magic).await.unwrap();
+    channel.execute(pingpong::PingPongProtocol::new(0x0100)).await.unwrap();
 }
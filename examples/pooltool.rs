@@ -1,8 +1,11 @@
-/**
+/*
 © 2020 PERLUR Group
 SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 */
 
+/* Sample listener, not wired into any example binary. */
+#![allow(dead_code)]
+
 use cardano_ouroboros_network::{
     BlockHeader,
     protocols::chainsync::Listener,
@@ -71,9 +74,9 @@ impl Listener for PoolTool {
                 .stdin(Stdio::null())
                 .stdout(Stdio::piped())
                 .output()
-                .expect(&*format!("Failed to execute {:?}", &self.cardano_node_path));
+                .unwrap_or_else(|_| panic!("Failed to execute {:?}", &self.cardano_node_path));
             let version_string = String::from_utf8_lossy(&output.stdout);
-            let cap = Regex::new("cardano-node (\\d+\\.\\d+\\.\\d+) .*\ngit rev ([a-f0-9]{5}).*").unwrap().captures(&*version_string).unwrap();
+            let cap = Regex::new("cardano-node (\\d+\\.\\d+\\.\\d+) .*\ngit rev ([a-f0-9]{5}).*").unwrap().captures(&version_string).unwrap();
             self.node_version = format!("{}:{}", cap.get(1).map_or("", |m| m.as_str()), cap.get(2).map_or("", |m| m.as_str()));
             info!("Checking cardano-node version: {}", &self.node_version);
             self.last_node_version_time = Instant::now();
@@ -1,4 +1,4 @@
-/**
+/*
 © 2020 PERLUR Group
 
 SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
@@ -6,37 +6,24 @@ SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 */
 
 use cardano_ouroboros_network::{
-    mux::tcp::Channel,
-    protocols::{
-        handshake,
-        pingpong,
-    },
+    protocols::pingpong::PingPongProtocol,
+    server::Server,
 };
-use std::net::{TcpListener, TcpStream};
-use log::{info, error};
-use futures::executor::block_on;
+use log::{error, info};
 
 mod common;
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let cfg = common::init();
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", cfg.port)).unwrap();
+    let server = Server::bind(format!("127.0.0.1:{}", cfg.port), cfg.magic).await.unwrap()
+        .responder(|| PingPongProtocol::expect(0x0100));
 
-    for stream in listener.incoming() {
-        match handle(stream.unwrap(), &cfg) {
-            Ok(_) => info!("connection closed"),
-            Err(e) => error!("connection failed: {}", e),
+    /* Serve until interrupted. */
+    server.run_until(async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("cannot wait for ctrl-c: {}", e);
         }
-    }
-}
-
-fn handle(stream: TcpStream, cfg: &common::Config) -> Result<(), String> {
-    let channel = Channel::new(stream);
-
-    info!("new client!");
-    block_on(async {
-        channel.execute(handshake::HandshakeProtocol::expect(cfg.magic)).await?;
-        channel.execute(pingpong::PingPongProtocol::expect(0x0100)).await?;
-        Ok(())
-    })
+    }).await;
+    info!("server stopped");
 }
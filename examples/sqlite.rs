@@ -1,4 +1,4 @@
-/**
+/*
 Forked-off from https://github.com/AndrewWestberg/cncli/ on 2020-11-30
 © 2020 Andrew Westberg licensed under Apache-2.0
 
@@ -15,7 +15,7 @@ use std::{
 };
 use log::debug;
 use blake2b_simd::Params;
-use rusqlite::{Connection, Error, named_params, NO_PARAMS};
+use rusqlite::{Connection, Error, named_params};
 use cardano_ouroboros_network::{
     BlockStore,
     BlockHeader,
@@ -34,9 +34,9 @@ impl SQLiteBlockStore {
         {
             debug!("Intialize database.");
             db.execute_batch("PRAGMA journal_mode=WAL")?;
-            db.execute("CREATE TABLE IF NOT EXISTS db_version (version INTEGER PRIMARY KEY)", NO_PARAMS)?;
+            db.execute("CREATE TABLE IF NOT EXISTS db_version (version INTEGER PRIMARY KEY)", [])?;
             let mut stmt = db.prepare("SELECT version FROM db_version")?;
-            let mut rows = stmt.query(NO_PARAMS)?;
+            let mut rows = stmt.query([])?;
             let version: i64 = match rows.next()? {
                 None => { -1 }
                 Some(row) => {
@@ -69,11 +69,11 @@ impl SQLiteBlockStore {
                     protocol_major_version INTEGER NOT NULL, \
                     protocol_minor_version INTEGER NOT NULL, \
                     orphaned INTEGER NOT NULL DEFAULT 0 \
-                    )", NO_PARAMS)?;
-                db.execute("CREATE INDEX IF NOT EXISTS idx_chain_slot_number ON chain(slot_number)", NO_PARAMS)?;
-                db.execute("CREATE INDEX IF NOT EXISTS idx_chain_orphaned ON chain(orphaned)", NO_PARAMS)?;
-                db.execute("CREATE INDEX IF NOT EXISTS idx_chain_hash ON chain(hash)", NO_PARAMS)?;
-                db.execute("CREATE INDEX IF NOT EXISTS idx_chain_block_number ON chain(block_number)", NO_PARAMS)?;
+                    )", [])?;
+                db.execute("CREATE INDEX IF NOT EXISTS idx_chain_slot_number ON chain(slot_number)", [])?;
+                db.execute("CREATE INDEX IF NOT EXISTS idx_chain_orphaned ON chain(orphaned)", [])?;
+                db.execute("CREATE INDEX IF NOT EXISTS idx_chain_hash ON chain(hash)", [])?;
+                db.execute("CREATE INDEX IF NOT EXISTS idx_chain_block_number ON chain(block_number)", [])?;
             }
 
             // Upgrade their database to version 2
@@ -87,18 +87,18 @@ impl SQLiteBlockStore {
                     slots TEXT NOT NULL, \
                     hash TEXT NOT NULL,
                     UNIQUE(epoch,pool_id)
-                )", NO_PARAMS)?;
+                )", [])?;
             }
 
             // Update the db version now that we've upgraded the user's database fully
             if version < 0 {
-                db.execute("INSERT INTO db_version (version) VALUES (?1)", &[&SQLiteBlockStore::DB_VERSION])?;
+                db.execute("INSERT INTO db_version (version) VALUES (?1)", [SQLiteBlockStore::DB_VERSION])?;
             } else {
-                db.execute("UPDATE db_version SET version=?1", &[&SQLiteBlockStore::DB_VERSION])?;
+                db.execute("UPDATE db_version SET version=?1", [SQLiteBlockStore::DB_VERSION])?;
             }
         }
 
-        Ok(SQLiteBlockStore { db: db })
+        Ok(SQLiteBlockStore { db })
     }
 
     fn sql_save_block(&mut self, pending_blocks: &mut Vec<BlockHeader>, network_magic: u32) -> Result<(), rusqlite::Error> {
@@ -108,7 +108,7 @@ impl SQLiteBlockStore {
         let mut prev_eta_v =
             {
                 hex::decode(
-                    match db.query_row("SELECT eta_v, max(slot_number) FROM chain WHERE orphaned = 0", NO_PARAMS, |row| row.get(0)) {
+                    match db.query_row("SELECT eta_v, max(slot_number) FROM chain WHERE orphaned = 0", [], |row| row.get(0)) {
                         Ok(eta_v) => { eta_v }
                         Err(_) => {
                             if network_magic == 764824073 {
@@ -169,13 +169,13 @@ impl SQLiteBlockStore {
 
             for block in pending_blocks.drain(..) {
                 // Set any necessary blocks as orphans
-                let orphan_num = orphan_stmt.execute(&[&block.block_number])?;
+                let orphan_num = orphan_stmt.execute([block.block_number])?;
 
                 if orphan_num > 0 {
                     // get the last block eta_v (nonce) in the db
                     prev_eta_v = {
                         hex::decode(
-                            match tx.query_row("SELECT eta_v, max(slot_number) FROM chain WHERE orphaned = 0", NO_PARAMS, |row| row.get(0)) {
+                            match tx.query_row("SELECT eta_v, max(slot_number) FROM chain WHERE orphaned = 0", [], |row| row.get(0)) {
                                 Ok(eta_v) => { eta_v }
                                 Err(_) => {
                                     if network_magic == 764824073 {
@@ -191,12 +191,12 @@ impl SQLiteBlockStore {
                     };
                 }
                 // blake2b hash of eta_vrf_0
-                let mut block_eta_v = Params::new().hash_length(32).to_state().update(&*block.eta_vrf_0).finalize().as_bytes().to_vec();
+                let mut block_eta_v = Params::new().hash_length(32).to_state().update(&block.eta_vrf_0).finalize().as_bytes().to_vec();
                 prev_eta_v.append(&mut block_eta_v);
                 // blake2b hash of prev_eta_v + block_eta_v
-                prev_eta_v = Params::new().hash_length(32).to_state().update(&*prev_eta_v).finalize().as_bytes().to_vec();
+                prev_eta_v = Params::new().hash_length(32).to_state().update(&prev_eta_v).finalize().as_bytes().to_vec();
 
-                insert_stmt.execute_named(
+                insert_stmt.execute(
                     named_params! {
                     ":block_number" : block.block_number,
                     ":slot_number": block.slot_number,
@@ -229,17 +229,17 @@ impl SQLiteBlockStore {
 }
 
 impl BlockStore for SQLiteBlockStore {
-    fn save_block(&mut self, mut pending_blocks: &mut Vec<BlockHeader>, network_magic: u32) -> io::Result<()> {
-        match self.sql_save_block(&mut pending_blocks, network_magic) {
+    fn save_block(&mut self, pending_blocks: &mut Vec<BlockHeader>, network_magic: u32) -> io::Result<()> {
+        match self.sql_save_block(pending_blocks, network_magic) {
             Ok(_) => Ok(()),
-            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Database error!")),
+            Err(_) => Err(io::Error::other("Database error!")),
         }
     }
 
     fn load_blocks(&mut self) -> Option<Vec<(i64, Vec<u8>)>> {
         let db = &self.db;
         let mut stmt = db.prepare("SELECT slot_number, hash FROM chain where orphaned = 0 ORDER BY slot_number DESC LIMIT 33").unwrap();
-        let blocks = stmt.query_map(NO_PARAMS, |row| {
+        let blocks = stmt.query_map([], |row| {
             let slot_result: Result<i64, Error> = row.get(0);
             let hash_result: Result<String, Error> = row.get(1);
             let slot = slot_result?;
@@ -1,4 +1,4 @@
-/**
+/*
 © 2020 PERLUR Group
 
 SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
@@ -6,25 +6,33 @@ SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 */
 
 use cardano_ouroboros_network::{
-    mux,
-    protocols::chainsync::{ChainSyncProtocol, Mode},
+    protocols::{
+        chainsync::{ChainSyncProtocol, Mode},
+        transaction::TxSubmissionProtocol,
+    },
+    supervisor::Supervisor,
 };
-use futures::executor::block_on;
+use log::{error, info};
 
 mod common;
 mod sqlite;
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let cfg = common::init();
+    let (db, magic) = (cfg.db.clone(), cfg.magic);
 
-    block_on(async {
-        let channel = mux::tcp::connect(&cfg.host, cfg.port).await.unwrap();
-        channel.handshake(cfg.magic).await.unwrap();
-        channel.execute({ChainSyncProtocol {
+    /* Each connection opens the store anew and syncs on from the last block stored. */
+    let supervisor = Supervisor::new(cfg.host, cfg.port, cfg.magic)
+        .protocol(move || ChainSyncProtocol {
             mode: Mode::Sync,
-            network_magic: cfg.magic,
-            store: Some(Box::new(sqlite::SQLiteBlockStore::new(&cfg.db).unwrap())),
+            network_magic: magic,
+            store: Some(Box::new(sqlite::SQLiteBlockStore::new(&db).unwrap())),
             ..Default::default()
-        }}).await.unwrap();
-    });
+        })
+        .protocol(TxSubmissionProtocol::default)
+        .on_reconnect(|attempt, error| info!("reconnecting after {}, attempt {}", error, attempt));
+    if let Err(e) = supervisor.run().await {
+        error!("sync failed: {}", e);
+    }
 }
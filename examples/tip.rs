@@ -1,4 +1,4 @@
-/**
+/*
 © 2020 PERLUR Group
 
 SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
@@ -7,13 +7,10 @@ SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 
 use cardano_ouroboros_network::{
     BlockHeader,
-    mux,
     protocols::chainsync::{ChainSyncProtocol, Mode, Listener},
+    supervisor::Supervisor,
 };
-use futures::{
-    executor::block_on,
-};
-use log::info;
+use log::{error, info};
 
 mod common;
 
@@ -25,17 +22,20 @@ impl Listener for Handler {
     }
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let cfg = common::init();
+    let magic = cfg.magic;
 
-    block_on(async {
-        let channel = mux::tcp::connect(&cfg.host, cfg.port).await.unwrap();
-        channel.handshake(cfg.magic).await.unwrap();
-        channel.execute(ChainSyncProtocol {
+    let supervisor = Supervisor::new(cfg.host, cfg.port, cfg.magic)
+        .protocol(move || ChainSyncProtocol {
             mode: Mode::SendTip,
-            network_magic: cfg.magic,
+            network_magic: magic,
             notify: Some(Box::new(Handler {})),
             ..Default::default()
-        }).await.unwrap();
-    });
+        })
+        .on_reconnect(|attempt, error| info!("reconnecting after {}, attempt {}", error, attempt));
+    if let Err(e) = supervisor.run().await {
+        error!("tip failed: {}", e);
+    }
 }
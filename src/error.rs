@@ -0,0 +1,76 @@
+/*
+© 2020 PERLUR Group
+
+SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
+
+*/
+
+use std::{
+    fmt,
+    io,
+    sync::Arc,
+};
+
+use crate::protocols::handshake::Refusal;
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    // Connecting, reading or writing failed, usually worth a retry
+    Io(Arc<io::Error>),
+    // A message could not be decoded
+    Decode(String),
+    // The peer, or one of our protocols, broke the rules of a mini-protocol or of the multiplexer
+    ProtocolViolation(String),
+    // One side did not agree to talk to the other
+    HandshakeRefused(Refusal),
+    // The peer did not respond in time
+    Timeout(String),
+    // The peer closed the connection
+    Disconnected,
+    // Another protocol is already running on the subchannel
+    SubchannelInUse(u16),
+    // The call cannot be carried out with the arguments given
+    InvalidInput(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "I/O error: {}", error),
+            Error::Decode(message) => write!(f, "decode error: {}", message),
+            Error::ProtocolViolation(message) => write!(f, "protocol violation: {}", message),
+            Error::HandshakeRefused(refusal) => write!(f, "handshake refused: {}", refusal),
+            Error::Timeout(message) => write!(f, "timeout: {}", message),
+            Error::Disconnected => write!(f, "peer disconnected"),
+            Error::SubchannelInUse(id) => write!(f, "subchannel {:04x} already in use", id),
+            Error::InvalidInput(message) => write!(f, "invalid input: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::BrokenPipe => Error::Disconnected,
+            io::ErrorKind::TimedOut => Error::Timeout(error.to_string()),
+            _ => Error::Io(Arc::new(error)),
+        }
+    }
+}
+
+impl From<serde_cbor::Error> for Error {
+    fn from(error: serde_cbor::Error) -> Self {
+        Error::Decode(error.to_string())
+    }
+}
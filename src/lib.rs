@@ -1,4 +1,4 @@
-/**
+/*
 Forked-off from https://github.com/AndrewWestberg/cncli/ on 2020-11-30
 © 2020 Andrew Westberg licensed under Apache-2.0
 
@@ -9,17 +9,28 @@ SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 
 */
 
+mod error;
 pub mod mux;
 pub mod protocols;
+pub mod server;
+pub mod supervisor;
+#[cfg(test)]
+mod testing;
 
-use std::io;
+pub use error::Error;
 
-pub trait Protocol {
+use std::{
+    io,
+    time::Duration,
+};
+
+/* Protocols are driven by tasks that may move between threads, hence Send. */
+pub trait Protocol: Send {
     // Each protocol has a unique hardcoded id
     fn protocol_id(&self) -> u16;
 
     // Each protocol can provide a result
-    fn result(&self) -> Result<String, String>;
+    fn result(&self) -> Result<String, Error>;
 
     // We have a client or server role in the protocol
     fn role(&self) -> Agency;
@@ -30,12 +41,74 @@ pub trait Protocol {
     // Printable version of the state for the Protocol
     fn state(&self) -> String;
 
+    // How long the peer may take to send its next message in the current state, the Channel
+    // gives up with a timeout error after that. None waits forever.
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    // Most bytes the peer may have queued up for this protocol before we drop the connection
+    fn ingress_limit(&self) -> usize {
+        mux::DEFAULT_INGRESS_LIMIT
+    }
+
     // Fetch the next piece of data this protocol wants to send, or None if the client doesn't
-    // have agency.
+    // have agency. Returning None without changing agency or state fails the subchannel, as
+    // there is nothing to wait for.
     fn send_data(&mut self) -> Option<Vec<u8>>;
 
-    // Process data received from the remote server destined for this protocol
-    fn receive_data(&mut self, data: Vec<u8>);
+    // Called instead of send_data once the Channel is closing, returns the message that ends the
+    // protocol if there is one for the current state. None carries on with send_data.
+    fn terminate(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    // Process data received from the remote server destined for this protocol, an error tears
+    // down the connection
+    fn receive_data(&mut self, data: Vec<u8>) -> Result<(), Error>;
+}
+
+/* Lets protocols picked at runtime run on a Channel just like concrete ones. */
+impl<P: Protocol + ?Sized> Protocol for Box<P> {
+    fn protocol_id(&self) -> u16 {
+        (**self).protocol_id()
+    }
+
+    fn result(&self) -> Result<String, Error> {
+        (**self).result()
+    }
+
+    fn role(&self) -> Agency {
+        (**self).role()
+    }
+
+    fn agency(&self) -> Agency {
+        (**self).agency()
+    }
+
+    fn state(&self) -> String {
+        (**self).state()
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        (**self).timeout()
+    }
+
+    fn ingress_limit(&self) -> usize {
+        (**self).ingress_limit()
+    }
+
+    fn send_data(&mut self) -> Option<Vec<u8>> {
+        (**self).send_data()
+    }
+
+    fn terminate(&mut self) -> Option<Vec<u8>> {
+        (**self).terminate()
+    }
+
+    fn receive_data(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        (**self).receive_data(data)
+    }
 }
 
 #[derive(Debug, PartialEq, Copy, Clone)]
@@ -48,7 +121,7 @@ pub enum Agency {
     None,
 }
 
-pub trait BlockStore {
+pub trait BlockStore: Send {
     fn save_block(&mut self, pending_blocks: &mut Vec<BlockHeader>, network_magic: u32) -> io::Result<()>;
     fn load_blocks(&mut self) -> Option<Vec<(i64, Vec<u8>)>>;
 }
@@ -1,8 +1,1009 @@
-/**
+/*
 © 2020 PERLUR Group
 
 SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 
 */
 
+mod bearer;
+pub mod capture;
+pub mod memory;
+mod metrics;
+mod rtt;
 pub mod tcp;
+#[cfg(unix)]
+pub mod unix;
+
+pub use bearer::{Bearer, Segment, StreamBearer};
+pub use metrics::{Metrics, ProtocolMetrics};
+pub use rtt::RoundTrip;
+use metrics::message_id;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryFrom,
+    future::poll_fn,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use log::{debug, trace};
+use tokio::{
+    sync::{mpsc, Notify},
+    task::{AbortHandle, JoinHandle},
+    time::timeout_at,
+};
+
+use crate::{
+    Agency, Error, Protocol,
+    mux::capture::Direction,
+    protocols::handshake::{Handshake, HandshakeProtocol, VersionTable},
+};
+
+/* Number of messages each subchannel can queue up for the writer before sending waits. */
+const QUEUE_SIZE: usize = 4;
+
+/* Largest payload sent in a single segment unless configured otherwise. */
+pub const DEFAULT_SDU_SIZE: usize = 12288;
+
+/* Ingress limit of protocols that do not declare their own. */
+pub const DEFAULT_INGRESS_LIMIT: usize = 0x100000;
+
+type Subchannels = HashMap<u16, SubchannelEntry>;
+
+/* The queue a subchannel writes its messages to, handed to the writer when it registers. */
+type Outbox = (u16, mpsc::Receiver<Vec<u8>>);
+
+/* What the reader task needs to know about a subchannel to dispatch segments to it. */
+struct SubchannelEntry {
+    sender: mpsc::UnboundedSender<Result<Vec<u8>, Error>>,
+    role: Agency,
+    /* Kept in sync by the subchannel, data is only accepted while the peer has agency. */
+    agency: Agency,
+    /* Bytes received but not yet consumed as messages by the subchannel. */
+    queued: Arc<AtomicUsize>,
+    limit: usize,
+    /* When the last request waiting for a response was written. */
+    request_sent: Option<Duration>,
+}
+
+impl SubchannelEntry {
+    fn awaits_peer(&self) -> bool {
+        self.agency != self.role && self.agency != Agency::None
+    }
+
+    fn enqueue(&self, payload: Vec<u8>) -> Result<(), Error> {
+        let queued = self.queued.fetch_add(payload.len(), Ordering::Relaxed) + payload.len();
+        if queued > self.limit {
+            return Err(Error::ProtocolViolation(format!(
+                "ingress queue overrun, {} bytes queued with a limit of {}", queued, self.limit,
+            )));
+        }
+        let _ = self.sender.send(Ok(payload));
+        Ok(())
+    }
+}
+
+/*
+ * A connection is served by two tasks spawned on the current runtime. The reader task waits
+ * for incoming segments and dispatches them to the queues of the registered subchannels, the
+ * writer task takes turns draining the queue of each subchannel into the bearer. Neither of them ever
+ * blocks the executor, so many channels and their subchannels can share a single thread.
+ * Channels are Send and Sync and each subchannel can be driven from a task of its own, so
+ * they work just as well on a multi-threaded runtime.
+ */
+pub struct Channel {
+    shared: Arc<Mutex<ChannelShared>>,
+    outboxes: mpsc::UnboundedSender<Outbox>,
+    sdu_size: Arc<AtomicUsize>,
+    /* Signalled whenever a subchannel stops. */
+    stopped: Arc<Notify>,
+    reader: Reader,
+    writer: JoinHandle<()>,
+}
+
+/*
+ * Dropping the Channel stops reading right away and fails the subchannels still running, so
+ * none of them waits for data that never comes. The writer drains the queues first and shuts
+ * the bearer down once the last subchannel is gone.
+ */
+struct Reader {
+    task: JoinHandle<()>,
+    shared: Arc<Mutex<ChannelShared>>,
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        self.task.abort();
+        if let Ok(mut shared) = self.shared.lock() {
+            if shared.error.is_none() {
+                shared.fail(Error::Disconnected);
+            }
+        }
+    }
+}
+
+impl Channel {
+    /* Must be called from within a tokio runtime as it spawns the bearer tasks. */
+    pub fn new(bearer: impl Bearer) -> Self {
+        let bearer: Arc<dyn Bearer> = Arc::new(bearer);
+        let (outboxes, registrations) = mpsc::unbounded_channel();
+        let shared = Arc::new(Mutex::new(ChannelShared {
+            start_time: Instant::now(),
+            protocols: HashMap::new(),
+            limits: HashMap::new(),
+            metrics: Metrics::default(),
+            rtt: rtt::Estimator::default(),
+            closing: false,
+            initiator: false,
+            responder: false,
+            error: None,
+            tasks: Vec::new(),
+        }));
+        let sdu_size = Arc::new(AtomicUsize::new(DEFAULT_SDU_SIZE));
+        /* The writer task finishes on its own once all queues are drained and closed. */
+        let writer = tokio::spawn(write_segments(bearer.clone(), registrations, shared.clone(), sdu_size.clone()));
+        let reader = tokio::spawn(read_segments(bearer, shared.clone()));
+        shared.lock().unwrap().tasks = vec![writer.abort_handle(), reader.abort_handle()];
+        let reader = Reader { task: reader, shared: shared.clone() };
+        Channel { shared, outboxes, sdu_size, stopped: Arc::new(Notify::new()), reader, writer }
+    }
+
+    /* Largest payload per segment from now on, limited to what the segment header can carry. */
+    pub fn set_sdu_size(&self, size: usize) {
+        self.sdu_size.store(size.clamp(1, u16::MAX as usize), Ordering::Relaxed);
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.shared.lock().unwrap().start_time.elapsed()
+    }
+
+    /* Snapshot of the traffic counters of this connection. */
+    pub fn metrics(&self) -> Metrics {
+        self.shared.lock().unwrap().metrics.clone()
+    }
+
+    /* Round trip time and peer clock estimates, measured on every request answered by the peer. */
+    pub fn round_trip(&self) -> RoundTrip {
+        self.shared.lock().unwrap().rtt.round_trip()
+    }
+
+    /* Override the ingress limit the protocol with this id declares. */
+    pub fn set_ingress_limit(&self, protocol_id: u16, limit: usize) {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(subchannel) = shared.protocols.get_mut(&protocol_id) {
+            subchannel.limit = limit;
+        }
+        shared.limits.insert(protocol_id, limit);
+    }
+
+    /*
+     * Close the connection gracefully. Every running protocol sends its termination message
+     * as soon as it has agency, once all of them are done the bearer is shut down. If that
+     * takes longer than the timeout, the connection is dropped instead and a timeout error is
+     * returned.
+     */
+    pub async fn close(self, within: Duration) -> Result<(), Error> {
+        let Channel { shared, outboxes, stopped, reader, writer, .. } = self;
+        let deadline = Instant::now() + within;
+        let forced = |reason: &str| {
+            let error = Error::Timeout(format!("{}, connection dropped", reason));
+            shared.lock().unwrap().teardown(error.clone());
+            Err(error)
+        };
+        shared.lock().unwrap().closing = true;
+
+        let finished = async {
+            loop {
+                let notified = stopped.notified();
+                if shared.lock().unwrap().protocols.is_empty() {
+                    break;
+                }
+                notified.await;
+            }
+        };
+        if timeout_at(deadline.into(), finished).await.is_err() {
+            return forced("protocols did not finish in time");
+        }
+
+        /* The writer shuts the bearer down once everything queued is out. */
+        drop(outboxes);
+        let flushed = timeout_at(deadline.into(), writer).await.is_ok();
+        drop(reader);
+        if !flushed {
+            return forced("queued data was not sent in time");
+        }
+        debug!("connection closed");
+        Ok(())
+    }
+
+    /*
+     * Handshake with the versions of the table, a network magic stands for all node-to-node
+     * versions. Use VersionTable::node_to_client() on the local socket of a node.
+     */
+    pub async fn handshake(&self, versions: impl Into<VersionTable>) -> Result<Handshake, Error> {
+        let versions = versions.into();
+        if versions.is_query() {
+            return Err(Error::InvalidInput("version query passed to handshake, use query_versions".to_string()));
+        }
+        let handshake = self.register(HandshakeProtocol::new(versions))?.finish().await?;
+        handshake.negotiated().cloned()
+            .ok_or_else(|| Error::ProtocolViolation("handshake finished without a version".to_string()))
+    }
+
+    /*
+     * Ask the server which of the versions of the table it speaks instead of agreeing on one.
+     * The server closes the connection afterwards.
+     */
+    pub async fn query_versions(&self, versions: impl Into<VersionTable>) -> Result<VersionTable, Error> {
+        let handshake = self.register(HandshakeProtocol::new(versions.into().with_query()))?.finish().await?;
+        handshake.queried().cloned()
+            .ok_or_else(|| Error::ProtocolViolation("server accepted a version query".to_string()))
+    }
+
+    pub async fn execute(&self, protocol: impl Protocol + 'static) -> Result<String, Error> {
+        self.register(protocol)?.run().await
+    }
+
+    /*
+     * Attach a protocol to its subchannel right away, so that no data for it gets lost, and
+     * return a handle to drive it. Any number of subchannels can be driven concurrently.
+     */
+    pub fn register<P: Protocol + 'static>(&self, protocol: P) -> Result<Subchannel<P>, Error> {
+        let id = protocol.protocol_id();
+        let (sender, rx) = mpsc::unbounded_channel();
+        let (tx, outbox) = mpsc::channel(QUEUE_SIZE);
+        let queued = Arc::new(AtomicUsize::new(0));
+        {
+            let mut shared = self.shared.lock().unwrap();
+            if let Some(error) = &shared.error {
+                return Err(error.clone());
+            }
+            if shared.protocols.contains_key(&id) {
+                return Err(Error::SubchannelInUse(id));
+            }
+            match protocol.role() {
+                Agency::Client => shared.initiator = true,
+                Agency::Server => shared.responder = true,
+                Agency::None => {}
+            }
+            let limit = shared.limits.get(&id).copied().unwrap_or_else(|| protocol.ingress_limit());
+            shared.protocols.insert(id, SubchannelEntry {
+                sender,
+                role: protocol.role(),
+                agency: protocol.agency(),
+                queued: queued.clone(),
+                limit,
+                request_sent: None,
+            });
+        }
+        /* Should the writer be gone, sending fails with the queue dropped. */
+        let _ = self.outboxes.send((id, outbox));
+        trace!("started subchannel {:04x}", id);
+        Ok(Subchannel {
+            id,
+            protocol,
+            shared: self.shared.clone(),
+            rx,
+            tx,
+            buffer: Reassembly::default(),
+            queued,
+            _registration: Registration {
+                id,
+                shared: self.shared.clone(),
+                stopped: self.stopped.clone(),
+            },
+        })
+    }
+}
+
+struct ChannelShared {
+    start_time: Instant,
+    protocols: Subchannels,
+    /* Ingress limits configured on the channel, by protocol id. */
+    limits: HashMap<u16, usize>,
+    metrics: Metrics,
+    rtt: rtt::Estimator,
+    /* Set by Channel::close, protocols terminate once they have agency. */
+    closing: bool,
+    /*
+     * Roles this end has taken on, each with the first protocol registered in it. A duplex
+     * connection has both, the mode bit of every segment from the peer must match one of them.
+     */
+    initiator: bool,
+    responder: bool,
+    error: Option<Error>,
+    /* Bearer tasks, the bearer itself is dropped once both are gone. */
+    tasks: Vec<AbortHandle>,
+}
+
+impl ChannelShared {
+    /*
+     * Find the subchannel a segment from the peer belongs to. Initiator segments go to our
+     * responder and the other way round, so the mode bit must match a role this end has taken
+     * on. The subchannel must be running and waiting for the peer to send, anything else
+     * would be lost and fails the connection.
+     */
+    fn lookup(&self, segment: &Segment) -> Result<&SubchannelEntry, Error> {
+        let from_initiator = segment.protocol_id & 0x8000 == 0;
+        if from_initiator && !self.responder {
+            return Err(Error::ProtocolViolation(format!(
+                "initiator segment for {:04x} while we are no responder", segment.protocol_id,
+            )));
+        }
+        if !from_initiator && !self.initiator {
+            return Err(Error::ProtocolViolation(format!(
+                "responder segment for {:04x} while we are no initiator", segment.protocol_id,
+            )));
+        }
+        match self.protocols.get(&(segment.protocol_id ^ 0x8000)) {
+            Some(subchannel) if subchannel.awaits_peer() => Ok(subchannel),
+            Some(_) => Err(Error::ProtocolViolation(format!(
+                "segment for {:04x} while the peer has no agency", segment.protocol_id,
+            ))),
+            None => Err(Error::ProtocolViolation(format!(
+                "segment for {:04x} which is not running", segment.protocol_id,
+            ))),
+        }
+    }
+
+    /* Fail all current and future subchannels. */
+    fn fail(&mut self, error: Error) {
+        for (_, subchannel) in self.protocols.drain() {
+            let _ = subchannel.sender.send(Err(error.clone()));
+        }
+        self.error = Some(error);
+    }
+
+    /* Fail the channel and drop the connection without flushing what is still queued. */
+    fn teardown(&mut self, error: Error) {
+        debug!("tearing down connection: {}", error);
+        self.fail(error);
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+    }
+}
+
+/* A single protocol running on its own subchannel of a Channel. */
+pub struct Subchannel<P> {
+    id: u16,
+    protocol: P,
+    shared: Arc<Mutex<ChannelShared>>,
+    rx: mpsc::UnboundedReceiver<Result<Vec<u8>, Error>>,
+    tx: mpsc::Sender<Vec<u8>>,
+    /* Received bytes not yet forming a complete message. */
+    buffer: Reassembly,
+    queued: Arc<AtomicUsize>,
+    _registration: Registration,
+}
+
+/* Frees the subchannel once its protocol is gone. */
+struct Registration {
+    id: u16,
+    shared: Arc<Mutex<ChannelShared>>,
+    stopped: Arc<Notify>,
+}
+
+impl<P: Protocol> Subchannel<P> {
+    /*
+     * Drive the protocol until it runs out of agency. A peer that sends something we cannot
+     * handle tears down the whole connection, other connections are not affected.
+     */
+    pub async fn run(mut self) -> Result<String, Error> {
+        self.complete().await
+    }
+
+    /* Like run() but hands back the finished protocol to look at its outcome. */
+    pub async fn finish(mut self) -> Result<P, Error> {
+        self.complete().await?;
+        Ok(self.protocol)
+    }
+
+    async fn complete(&mut self) -> Result<String, Error> {
+        match self.drive().await {
+            Err(error @ (Error::Decode(_) | Error::ProtocolViolation(_) | Error::Timeout(_))) => {
+                self.shared.lock().unwrap().teardown(error.clone());
+                Err(error)
+            }
+            result => result,
+        }
+    }
+
+    async fn drive(&mut self) -> Result<String, Error> {
+        loop {
+            let agency = self.protocol.agency();
+            if agency == Agency::None {
+                return self.protocol.result();
+            }
+
+            let state = self.protocol.state();
+            if agency == self.protocol.role() {
+                let closing = self.shared.lock().unwrap().closing;
+                let payload = if closing {
+                    self.protocol.terminate().or_else(|| self.protocol.send_data())
+                } else {
+                    self.protocol.send_data()
+                };
+                match payload {
+                    Some(payload) => {
+                        /* The answer may arrive as soon as the payload is out. */
+                        self.transition(Direction::Sent, message_id(&payload), state);
+                        self.tx.send(payload).await
+                            .map_err(|_| Error::Disconnected)?;
+                    }
+                    /* Asking again would spin, unless the protocol moved on without a message. */
+                    None if self.protocol.agency() == agency && self.protocol.state() == state => {
+                        return Err(Error::ProtocolViolation(format!(
+                            "subchannel {:04x} has agency in state {} but nothing to send", self.id, state,
+                        )));
+                    }
+                    None => {}
+                }
+            } else {
+                let message = self.receive().await?;
+                let id = message_id(&message);
+                self.protocol.receive_data(message)?;
+                self.transition(Direction::Received, id, state);
+            }
+        }
+    }
+
+    /* Publish the agency after a message and count it. */
+    fn transition(&self, direction: Direction, message_id: Option<u64>, from: String) {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(subchannel) = shared.protocols.get_mut(&self.id) {
+            subchannel.agency = self.protocol.agency();
+        }
+        shared.metrics.message(self.id, direction, message_id, from, self.protocol.state());
+    }
+
+    /* Wait for the next complete message within the timeout of the current state. */
+    async fn receive(&mut self) -> Result<Vec<u8>, Error> {
+        let deadline = self.protocol.timeout().map(|timeout| Instant::now() + timeout);
+        loop {
+            if let Some(message) = self.buffer.next_message()? {
+                self.queued.fetch_sub(message.len(), Ordering::Relaxed);
+                return Ok(message);
+            }
+            let payload = match deadline {
+                Some(deadline) => timeout_at(deadline.into(), self.rx.recv()).await
+                    .map_err(|_| Error::Timeout(format!(
+                        "no message on subchannel {:04x} in state {}", self.id, self.protocol.state(),
+                    )))?,
+                None => self.rx.recv().await,
+            };
+            match payload {
+                Some(payload) => self.buffer.extend(payload?),
+                None => return Err(Error::Disconnected),
+            }
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Ok(mut shared) = self.shared.lock() {
+            shared.protocols.remove(&self.id);
+        }
+        self.stopped.notify_waiters();
+        trace!("stopped subchannel {:04x}", self.id);
+    }
+}
+
+/*
+ * Collects the payload of a subchannel and splits it into CBOR items. Only the item headers
+ * are looked at and the scan resumes where the previous segment ended, so a message spread
+ * over many segments is not parsed again each time one of them arrives.
+ */
+struct Reassembly {
+    buffer: Vec<u8>,
+    // Start of the next header to scan, beyond the buffer while string content is missing
+    offset: usize,
+    // Items each open array, map or tag still needs, None for indefinite ones
+    open: Vec<Option<u64>>,
+}
+
+impl Default for Reassembly {
+    fn default() -> Self {
+        Reassembly { buffer: Vec::new(), offset: 0, open: vec![Some(1)] }
+    }
+}
+
+impl Reassembly {
+    fn extend(&mut self, data: Vec<u8>) {
+        self.buffer.extend(data);
+    }
+
+    /* Split off the first complete CBOR item, if the buffer already holds one. */
+    fn next_message(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        loop {
+            if self.offset > self.buffer.len() {
+                return Ok(None);
+            }
+            if self.open.is_empty() {
+                let rest = self.buffer.split_off(self.offset);
+                let message = std::mem::replace(&mut self.buffer, rest);
+                self.offset = 0;
+                self.open = vec![Some(1)];
+                return Ok(Some(message));
+            }
+            let initial = match self.buffer.get(self.offset) {
+                Some(&initial) => initial,
+                None => return Ok(None),
+            };
+            if initial == 0xff {
+                if self.open.pop() != Some(None) {
+                    return Err(Error::Decode(format!("unexpected break at offset {}", self.offset)));
+                }
+                self.offset += 1;
+                self.item_done();
+                continue;
+            }
+
+            let (major, info) = (initial >> 5, initial & 0x1f);
+            let size = match info {
+                0..=23 | 31 => 0,
+                24..=27 => 1 << (info - 24),
+                _ => return Err(Error::Decode(format!("invalid additional info {} at offset {}", info, self.offset))),
+            };
+            let header = match self.buffer.get(self.offset + 1..self.offset + 1 + size) {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+            let argument = match info {
+                0..=23 => info as u64,
+                _ => header.iter().fold(0, |argument, &byte| argument << 8 | byte as u64),
+            };
+            let indefinite = info == 31;
+            if indefinite && !(2..=5).contains(&major) {
+                return Err(Error::Decode(format!("invalid indefinite length at offset {}", self.offset)));
+            }
+            self.offset += 1 + size;
+
+            match major {
+                2 | 3 if !indefinite => {
+                    self.offset = usize::try_from(argument).ok()
+                        .and_then(|length| self.offset.checked_add(length))
+                        .ok_or_else(|| Error::Decode(format!("string of {} bytes is too long", argument)))?;
+                    self.item_done();
+                }
+                2..=5 if indefinite => self.open.push(None),
+                4 | 5 => {
+                    let items = if major == 5 { argument.checked_mul(2) } else { Some(argument) };
+                    match items {
+                        Some(0) => self.item_done(),
+                        Some(items) => self.open.push(Some(items)),
+                        None => return Err(Error::Decode(format!("map of {} entries is too long", argument))),
+                    }
+                }
+                6 => self.open.push(Some(1)),
+                _ => self.item_done(),
+            }
+        }
+    }
+
+    /* Count a finished item against the containers it is nested in. */
+    fn item_done(&mut self) {
+        while let Some(Some(items)) = self.open.last_mut() {
+            *items -= 1;
+            if *items > 0 {
+                break;
+            }
+            self.open.pop();
+        }
+    }
+}
+
+async fn read_segments(bearer: Arc<dyn Bearer>, shared: Arc<Mutex<ChannelShared>>) {
+    let start_time = shared.lock().unwrap().start_time;
+    loop {
+        match bearer.read_segment().await {
+            Ok(segment) => {
+                trace!("rx segment: timestamp {} protocol {:04x}", segment.timestamp, segment.protocol_id);
+                let received = start_time.elapsed();
+                let mut shared = shared.lock().unwrap();
+                let shared = &mut *shared;
+                shared.metrics.segment(segment.protocol_id ^ 0x8000, Direction::Received, segment.payload.len());
+                let peer_time = shared.rtt.peer_time(segment.timestamp);
+                if let Some(subchannel) = shared.protocols.get_mut(&(segment.protocol_id ^ 0x8000)) {
+                    if let Some(sent) = subchannel.request_sent.take() {
+                        shared.rtt.sample(sent, received, peer_time);
+                    }
+                }
+                let dispatched = shared.lookup(&segment)
+                    .and_then(|subchannel| subchannel.enqueue(segment.payload));
+                if let Err(error) = dispatched {
+                    shared.teardown(error);
+                    break;
+                }
+            }
+            Err(error) => {
+                debug!("segment read error: {:?}", error);
+                shared.lock().unwrap().fail(error.into());
+                break;
+            }
+        }
+    }
+}
+
+/*
+ * Messages being written, by protocol. Protocols with data take turns one SDU at a time, so a
+ * protocol sending lots of data cannot hold up the others.
+ */
+#[derive(Default)]
+struct Egress {
+    queues: HashMap<u16, VecDeque<Vec<u8>>>,
+    /* Bytes of the first message of each queue already written. */
+    offsets: HashMap<u16, usize>,
+    ready: VecDeque<u16>,
+}
+
+impl Egress {
+    fn push(&mut self, id: u16, payload: Vec<u8>) {
+        if payload.is_empty() {
+            return;
+        }
+        let queue = self.queues.entry(id).or_default();
+        if queue.is_empty() {
+            self.ready.push_back(id);
+        }
+        queue.push_back(payload);
+    }
+
+    /* Whether a message of the protocol is still being written. */
+    fn holds(&self, id: u16) -> bool {
+        matches!(self.queues.get(&id), Some(queue) if !queue.is_empty())
+    }
+
+    /* The next SDU to write and whether it completes a message. */
+    fn next_sdu(&mut self, sdu_size: usize) -> Option<(u16, Vec<u8>, bool)> {
+        let id = self.ready.pop_front()?;
+        let queue = self.queues.get_mut(&id)?;
+        let message = queue.front()?;
+        let offset = self.offsets.entry(id).or_default();
+        let end = message.len().min(*offset + sdu_size);
+        let sdu = message[*offset..end].to_vec();
+        let last = end == message.len();
+        if last {
+            queue.pop_front();
+            *offset = 0;
+        } else {
+            *offset = end;
+        }
+        if !queue.is_empty() {
+            self.ready.push_back(id);
+        }
+        Some((id, sdu, last))
+    }
+}
+
+async fn write_segments(
+    bearer: Arc<dyn Bearer>,
+    mut registrations: mpsc::UnboundedReceiver<Outbox>,
+    shared: Arc<Mutex<ChannelShared>>,
+    sdu_size: Arc<AtomicUsize>,
+) {
+    let start_time = shared.lock().unwrap().start_time;
+    let mut egress = Egress::default();
+    let mut outboxes: Vec<Outbox> = Vec::new();
+    let mut registering = true;
+    loop {
+        /*
+         * Take the next message of every subchannel done with its last one. Each subchannel only
+         * ever has one message here, so a full queue makes its own sender wait but no other.
+         */
+        let pending = poll_fn(|cx| {
+            while registering {
+                match registrations.poll_recv(cx) {
+                    Poll::Ready(Some(outbox)) => outboxes.push(outbox),
+                    Poll::Ready(None) => registering = false,
+                    Poll::Pending => break,
+                }
+            }
+            /* A protocol registered again keeps its order, the earlier queue comes first. */
+            outboxes.retain_mut(|(id, queue)| loop {
+                if egress.holds(*id) {
+                    return true;
+                }
+                match queue.poll_recv(cx) {
+                    Poll::Ready(Some(payload)) => egress.push(*id, payload),
+                    Poll::Ready(None) => return false,
+                    Poll::Pending => return true,
+                }
+            });
+            if !egress.ready.is_empty() {
+                Poll::Ready(true)
+            } else if !registering && outboxes.is_empty() {
+                Poll::Ready(false)
+            } else {
+                Poll::Pending
+            }
+        }).await;
+        if !pending {
+            break;
+        }
+        /* Fragments of a message may be interleaved with other protocols, the peer reassembles them. */
+        if let Some((id, sdu, last)) = egress.next_sdu(sdu_size.load(Ordering::Relaxed)) {
+            let length = sdu.len();
+            let sent = start_time.elapsed();
+            let segment = Segment {
+                timestamp: sent.as_micros() as u32,
+                protocol_id: id,
+                payload: sdu,
+            };
+            /* Time the response if the message hands agency to the peer. */
+            if last {
+                let mut shared = shared.lock().unwrap();
+                if let Some(subchannel) = shared.protocols.get_mut(&id).filter(|subchannel| subchannel.awaits_peer()) {
+                    subchannel.request_sent = Some(sent);
+                }
+            }
+            if let Err(error) = bearer.write_segment(segment).await {
+                trace!("tx error: {:?}", error);
+                return;
+            }
+            trace!("tx size: {}", length);
+            shared.lock().unwrap().metrics.segment(id, Direction::Sent, length);
+        }
+    }
+    if let Err(error) = bearer.close().await {
+        trace!("close error: {:?}", error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Scripted;
+
+    /* Sends a single request and expects a single response. */
+    fn one_shot(id: u16, role: Agency) -> Scripted {
+        Scripted::new(id, role, vec![vec![0x80]])
+    }
+
+    #[tokio::test]
+    async fn subchannels_run_concurrently() {
+        let (client_bearer, server_bearer) = memory::pair();
+
+        let (srv, cli) = tokio::join!(
+            async {
+                let server = Channel::new(server_bearer);
+                let first = server.register(one_shot(0x0101, Agency::Server)).unwrap();
+                let second = server.register(one_shot(0x0102, Agency::Server)).unwrap();
+                tokio::try_join!(first.run(), second.run())
+            },
+            async {
+                let client = Channel::new(client_bearer);
+                let first = client.register(one_shot(0x0101, Agency::Client)).unwrap();
+                let second = client.register(one_shot(0x0102, Agency::Client)).unwrap();
+                assert!(client.register(one_shot(0x0102, Agency::Client)).is_err());
+                tokio::try_join!(second.run(), first.run())
+            },
+        );
+        assert_eq!(srv.unwrap(), ("0101 received 1".to_string(), "0102 received 1".to_string()));
+        assert_eq!(cli.unwrap(), ("0102 received 1".to_string(), "0101 received 1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn large_messages_are_fragmented() {
+        let (client_bearer, server_bearer) = memory::pair();
+        let request = serde_cbor::to_vec(&serde_cbor::Value::Bytes(vec![0xaa; 200_000])).unwrap();
+        let response = serde_cbor::to_vec(&serde_cbor::Value::Bytes(vec![0x55; 70_000])).unwrap();
+
+        let (srv, cli) = tokio::join!(
+            async {
+                let server = Channel::new(server_bearer);
+                server.execute(Scripted::new(0x0101, Agency::Server, vec![response.clone()])).await
+            },
+            async {
+                let client = Channel::new(client_bearer);
+                client.set_sdu_size(1000);
+                client.execute(Scripted::new(0x0101, Agency::Client, vec![request.clone()])).await
+            },
+        );
+        assert_eq!(srv.unwrap(), format!("0101 received {}", request.len()));
+        assert_eq!(cli.unwrap(), format!("0101 received {}", response.len()));
+    }
+
+    #[tokio::test]
+    async fn silent_peer_times_out() {
+        let (client_bearer, server_bearer) = memory::pair();
+        let client = Channel::new(client_bearer);
+        /* The server never drives its subchannel, so the request goes unanswered. */
+        let server = Channel::new(server_bearer);
+        let _stuck = server.register(one_shot(0x0101, Agency::Server)).unwrap();
+        let protocol = Scripted { timeout: Some(Duration::from_millis(50)), ..one_shot(0x0101, Agency::Client) };
+        let start = Instant::now();
+        assert!(matches!(client.execute(protocol).await, Err(Error::Timeout(_))));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(client.register(one_shot(0x0102, Agency::Client)).is_err());
+    }
+
+    /* Let the server side push a message to a client that is not expecting it. */
+    async fn unexpected_message(server_protocol: Scripted) -> Result<String, Error> {
+        let (client_bearer, server_bearer) = memory::pair();
+        let client = Channel::new(client_bearer);
+        let server = Channel::new(server_bearer);
+        /* Holds agency, so nothing may arrive for it. */
+        let _busy = client.register(one_shot(0x0101, Agency::Client)).unwrap();
+        let waiting = client.register(Scripted { agency: Agency::Server, ..one_shot(0x0102, Agency::Client) }).unwrap();
+        /* The server may see the connection drop, only the client side matters here. */
+        let _ = server.execute(server_protocol).await;
+        waiting.run().await
+    }
+
+    #[tokio::test]
+    async fn segments_without_agency_are_rejected() {
+        let protocol = Scripted { agency: Agency::Server, ..one_shot(0x0101, Agency::Server) };
+        assert!(matches!(unexpected_message(protocol).await, Err(Error::ProtocolViolation(_))));
+    }
+
+    #[tokio::test]
+    async fn segments_with_wrong_mode_are_rejected() {
+        /* Sent with the initiator's mode bit to the initiator. */
+        let protocol = one_shot(0x0102, Agency::Client);
+        assert!(matches!(unexpected_message(protocol).await, Err(Error::ProtocolViolation(_))));
+    }
+
+    #[tokio::test]
+    async fn segments_for_unknown_protocols_are_rejected() {
+        let (client_bearer, server_bearer) = memory::pair();
+        let client = Channel::new(client_bearer);
+        let server = Channel::new(server_bearer);
+        let running = server.register(one_shot(0x0102, Agency::Server)).unwrap();
+        /* The server never registered 0x0101, so the request must not just vanish. */
+        let (srv, _) = tokio::join!(running.run(), client.execute(one_shot(0x0101, Agency::Client)));
+        assert!(matches!(srv, Err(Error::ProtocolViolation(_))));
+    }
+
+    #[tokio::test]
+    async fn ingress_limit_is_enforced() {
+        let (client_bearer, server_bearer) = memory::pair();
+        let response = serde_cbor::to_vec(&serde_cbor::Value::Bytes(vec![0x55; 200])).unwrap();
+
+        let (srv, cli) = tokio::join!(
+            async {
+                let server = Channel::new(server_bearer);
+                server.execute(Scripted::new(0x0101, Agency::Server, vec![response.clone()])).await
+            },
+            async {
+                let client = Channel::new(client_bearer);
+                client.set_ingress_limit(0x0101, 100);
+                client.execute(one_shot(0x0101, Agency::Client)).await
+            },
+        );
+        srv.unwrap();
+        assert!(matches!(cli, Err(Error::ProtocolViolation(_))));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn channels_work_across_threads() {
+        let (client_bearer, server_bearer) = memory::pair();
+        let client = Arc::new(Channel::new(client_bearer));
+        let server = Channel::new(server_bearer);
+
+        let srv = tokio::spawn(async move {
+            server.execute(one_shot(0x0101, Agency::Server)).await
+        });
+        let cli = tokio::spawn(client.register(one_shot(0x0101, Agency::Client)).unwrap().run());
+        let monitor = {
+            let client = client.clone();
+            tokio::spawn(async move { client.duration() })
+        };
+        assert_eq!(srv.await.unwrap().unwrap(), "0101 received 1");
+        assert_eq!(cli.await.unwrap().unwrap(), "0101 received 1");
+        assert!(monitor.await.unwrap() <= client.duration());
+    }
+
+    #[tokio::test]
+    async fn agency_without_message_fails() {
+        let (client_bearer, _server_bearer) = memory::pair();
+        let client = Channel::new(client_bearer);
+        let silent = client.execute(Scripted::new(0x0101, Agency::Client, Vec::new()));
+        let result = tokio::time::timeout(Duration::from_secs(1), silent).await.expect("protocol still spinning");
+        assert!(matches!(result, Err(Error::ProtocolViolation(_))));
+    }
+
+    #[tokio::test]
+    async fn dropped_channel_fails_subchannels() {
+        let (client_bearer, server_bearer) = memory::pair();
+        let client = Channel::new(client_bearer);
+        let server = Channel::new(server_bearer);
+        let waiting = tokio::spawn(server.register(one_shot(0x0101, Agency::Server)).unwrap().run());
+        let peer = tokio::spawn(client.register(Scripted { agency: Agency::Server, ..one_shot(0x0101, Agency::Client) }).unwrap().run());
+        tokio::task::yield_now().await;
+        drop(server);
+
+        let within = Duration::from_secs(1);
+        let waiting = tokio::time::timeout(within, waiting).await.expect("subchannel still waiting");
+        assert!(matches!(waiting.unwrap(), Err(Error::Disconnected)));
+        /* With its last subchannel gone the bearer is closed, so the peer notices as well. */
+        let peer = tokio::time::timeout(within, peer).await.expect("peer still waiting");
+        assert!(matches!(peer.unwrap(), Err(Error::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn busy_subchannels_do_not_hold_up_others() {
+        let path = std::env::temp_dir().join(format!("cardano-ouroboros-network-mux-{}.capture", std::process::id()));
+        let (client_bearer, server_bearer) = memory::pair();
+        let server = Channel::new(server_bearer);
+        /* Never driven, they just take whatever arrives. */
+        let _bulk = server.register(one_shot(0x0101, Agency::Server)).unwrap();
+        let _urgent = server.register(one_shot(0x0102, Agency::Server)).unwrap();
+        let client = Channel::new(capture::CaptureBearer::create(client_bearer, &path).unwrap());
+        client.set_sdu_size(1000);
+        let bulk = client.register(one_shot(0x0101, Agency::Client)).unwrap();
+        let urgent = client.register(one_shot(0x0102, Agency::Client)).unwrap();
+
+        /* Nothing is written before the first await, so the bulk messages are all queued first. */
+        let mut queued = 0;
+        while bulk.tx.try_send(vec![0xaa; 5000]).is_ok() {
+            queued += 1;
+        }
+        urgent.tx.send(vec![0x80]).await.unwrap();
+        drop((bulk, urgent));
+        client.close(Duration::from_secs(1)).await.unwrap();
+
+        let sent = capture::read_capture(&path).unwrap().into_iter()
+            .map(|record| record.segment.protocol_id)
+            .collect::<Vec<_>>();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(sent.len(), queued * 5 + 1);
+        /* The urgent message goes out in the first round, right after one bulk segment. */
+        assert_eq!(sent.iter().position(|&id| id == 0x0102), Some(1));
+    }
+
+    #[test]
+    fn egress_takes_turns() {
+        let mut egress = Egress::default();
+        egress.push(0x0003, vec![0xbb; 25]);
+        egress.push(0x0002, vec![0xaa; 5]);
+        egress.push(0x0003, vec![0xcc; 5]);
+        assert!(egress.holds(0x0003));
+        assert!(!egress.holds(0x0004));
+        let mut sdus = Vec::new();
+        while let Some((id, sdu, _)) = egress.next_sdu(10) {
+            sdus.push((id, sdu.len()));
+        }
+        assert_eq!(sdus, vec![(0x0003, 10), (0x0002, 5), (0x0003, 10), (0x0003, 5), (0x0003, 5)]);
+    }
+
+    #[test]
+    fn messages_are_reassembled() {
+        let message = serde_cbor::to_vec(&serde_cbor::Value::Bytes(vec![0xaa; 100])).unwrap();
+        let mut buffer = Reassembly::default();
+        buffer.extend(message[..50].to_vec());
+        assert_eq!(buffer.next_message().unwrap(), None);
+        buffer.extend(message[50..].to_vec());
+        buffer.extend(message[..10].to_vec());
+        assert_eq!(buffer.next_message().unwrap(), Some(message.clone()));
+        assert_eq!(buffer.next_message().unwrap(), None);
+        assert_eq!(buffer.buffer, message[..10].to_vec());
+
+        let mut invalid = Reassembly::default();
+        invalid.extend(vec![0xff]);
+        assert!(matches!(invalid.next_message(), Err(Error::Decode(_))));
+    }
+
+    #[test]
+    fn nested_messages_are_reassembled_byte_by_byte() {
+        /* [3, {1: h'aa..'}, 24(h'bb..'), [_ "ab", -1], 1.5] */
+        let mut message = hex::decode("8503a1015818").unwrap();
+        message.extend(vec![0xaa; 24]);
+        message.extend(hex::decode("d81843bbbbbb9f62616220fff93e00").unwrap());
+        let mut buffer = Reassembly::default();
+        for (i, byte) in message.iter().enumerate() {
+            assert_eq!(buffer.next_message().unwrap(), None, "complete after {} bytes", i);
+            buffer.extend(vec![*byte]);
+        }
+        buffer.extend(vec![0x00]);
+        assert_eq!(buffer.next_message().unwrap(), Some(message));
+        assert_eq!(buffer.next_message().unwrap(), Some(vec![0x00]));
+        assert_eq!(buffer.next_message().unwrap(), None);
+    }
+}
@@ -0,0 +1,109 @@
+/*
+© 2020 PERLUR Group
+
+SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
+
+*/
+
+use std::io;
+
+use async_trait::async_trait;
+use byteorder::{ByteOrder, NetworkEndian};
+use log::{log_enabled, trace};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::Mutex,
+};
+
+/* A single mux segment as it travels through a bearer. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub timestamp: u32,
+    pub protocol_id: u16,
+    pub payload: Vec<u8>,
+}
+
+impl Segment {
+    const HEADER_SIZE: usize = 8;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut msg = vec![0u8; Segment::HEADER_SIZE];
+        NetworkEndian::write_u32(&mut msg[0..4], self.timestamp);
+        NetworkEndian::write_u16(&mut msg[4..6], self.protocol_id);
+        NetworkEndian::write_u16(&mut msg[6..8], self.payload.len() as u16);
+        msg.extend_from_slice(&self.payload[..]);
+        msg
+    }
+
+    pub async fn read_from(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Segment> {
+        let mut header = [0u8; Segment::HEADER_SIZE];
+        stream.read_exact(&mut header).await?;
+        let length = NetworkEndian::read_u16(&header[6..]) as usize;
+        let mut payload = vec![0u8; length];
+        stream.read_exact(&mut payload).await?;
+        trace!("rx bytes: {} {}", hex::encode(header), hex::encode(&payload));
+        Ok(Segment {
+            timestamp: NetworkEndian::read_u32(&header[0..4]),
+            protocol_id: NetworkEndian::read_u16(&header[4..6]),
+            payload,
+        })
+    }
+
+    pub async fn write_to(&self, stream: &mut (impl AsyncWrite + Unpin)) -> io::Result<()> {
+        let msg = self.to_bytes();
+        if log_enabled!(log::Level::Trace) {
+            trace!("tx bytes: {}", hex::encode(&msg));
+        }
+        stream.write_all(&msg).await
+    }
+}
+
+/*
+ * The transport underneath a Channel. The Channel reads and writes segments from two separate
+ * tasks at the same time, so implementations must not serialize reading with writing.
+ */
+#[async_trait]
+pub trait Bearer: Send + Sync + 'static {
+    // Wait for the next segment from the peer
+    async fn read_segment(&self) -> io::Result<Segment>;
+
+    // Send a single segment to the peer, the payload always fits in one SDU
+    async fn write_segment(&self, segment: Segment) -> io::Result<()>;
+
+    // Stop sending, the peer sees the end of the stream
+    async fn close(&self) -> io::Result<()>;
+}
+
+/* Bearer over the two halves of any byte stream. */
+pub struct StreamBearer<R, W> {
+    reader: Mutex<R>,
+    writer: Mutex<W>,
+}
+
+impl<R, W> StreamBearer<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        StreamBearer {
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+#[async_trait]
+impl<R, W> Bearer for StreamBearer<R, W>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    async fn read_segment(&self) -> io::Result<Segment> {
+        Segment::read_from(&mut *self.reader.lock().await).await
+    }
+
+    async fn write_segment(&self, segment: Segment) -> io::Result<()> {
+        segment.write_to(&mut *self.writer.lock().await).await
+    }
+
+    async fn close(&self) -> io::Result<()> {
+        self.writer.lock().await.shutdown().await
+    }
+}
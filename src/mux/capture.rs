@@ -0,0 +1,255 @@
+/*
+© 2020 PERLUR Group
+
+SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
+
+*/
+
+use std::{
+    fs::File,
+    io,
+    io::{ErrorKind, Write},
+    path::Path,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use byteorder::{ByteOrder, NetworkEndian};
+use log::trace;
+use tokio::sync::{oneshot, watch};
+
+use crate::mux::{Bearer, Segment};
+
+/*
+ * A capture file is a sequence of records, each of them a direction byte, the capture time in
+ * microseconds as a big endian u64 and the segment exactly as it travels over the wire.
+ */
+const RECORD_HEADER_SIZE: usize = 9;
+const SEGMENT_HEADER_SIZE: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Direction {
+    // Segment sent by the peer
+    Received,
+    // Segment sent by us
+    Sent,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    // Time since the capture started
+    pub time: Duration,
+    pub direction: Direction,
+    pub segment: Segment,
+}
+
+impl Record {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; RECORD_HEADER_SIZE];
+        bytes[0] = match self.direction {
+            Direction::Received => 0,
+            Direction::Sent => 1,
+        };
+        NetworkEndian::write_u64(&mut bytes[1..9], self.time.as_micros() as u64);
+        bytes.extend(self.segment.to_bytes());
+        bytes
+    }
+}
+
+/* Read all records of a capture file. */
+pub fn read_capture(path: impl AsRef<Path>) -> io::Result<Vec<Record>> {
+    let data = std::fs::read(path)?;
+    let truncated = || io::Error::new(ErrorKind::UnexpectedEof, "truncated capture file");
+    let mut records = Vec::new();
+    let mut rest = &data[..];
+    while !rest.is_empty() {
+        if rest.len() < RECORD_HEADER_SIZE + SEGMENT_HEADER_SIZE {
+            return Err(truncated());
+        }
+        let direction = match rest[0] {
+            0 => Direction::Received,
+            1 => Direction::Sent,
+            other => return Err(io::Error::new(ErrorKind::InvalidData, format!("unknown direction {}", other))),
+        };
+        let time = Duration::from_micros(NetworkEndian::read_u64(&rest[1..9]));
+        let header = &rest[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + SEGMENT_HEADER_SIZE];
+        let length = NetworkEndian::read_u16(&header[6..8]) as usize;
+        let end = RECORD_HEADER_SIZE + SEGMENT_HEADER_SIZE + length;
+        if rest.len() < end {
+            return Err(truncated());
+        }
+        records.push(Record {
+            time,
+            direction,
+            segment: Segment {
+                timestamp: NetworkEndian::read_u32(&header[0..4]),
+                protocol_id: NetworkEndian::read_u16(&header[4..6]),
+                payload: rest[RECORD_HEADER_SIZE + SEGMENT_HEADER_SIZE..end].to_vec(),
+            },
+        });
+        rest = &rest[end..];
+    }
+    Ok(records)
+}
+
+/*
+ * Records every segment going through the wrapped bearer to a capture file. The file is
+ * written by a thread of its own, so the connection never waits for the disk. Closing the
+ * bearer waits until everything recorded so far is written.
+ */
+pub struct CaptureBearer<B> {
+    inner: B,
+    start_time: Instant,
+    records: mpsc::Sender<Command>,
+}
+
+enum Command {
+    Write(Vec<u8>),
+    Flush(oneshot::Sender<()>),
+}
+
+impl<B: Bearer> CaptureBearer<B> {
+    pub fn create(inner: B, path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let (records, commands) = mpsc::channel();
+        thread::spawn(move || {
+            for command in commands {
+                match command {
+                    /* A broken capture must not break the connection. */
+                    Command::Write(bytes) => if let Err(error) = file.write_all(&bytes) {
+                        trace!("capture error: {:?}", error);
+                    },
+                    Command::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        Ok(CaptureBearer {
+            inner,
+            start_time: Instant::now(),
+            records,
+        })
+    }
+
+    fn record(&self, direction: Direction, segment: &Segment) {
+        let record = Record {
+            time: self.start_time.elapsed(),
+            direction,
+            segment: segment.clone(),
+        };
+        let _ = self.records.send(Command::Write(record.to_bytes()));
+    }
+}
+
+#[async_trait]
+impl<B: Bearer> Bearer for CaptureBearer<B> {
+    async fn read_segment(&self) -> io::Result<Segment> {
+        let segment = self.inner.read_segment().await?;
+        self.record(Direction::Received, &segment);
+        Ok(segment)
+    }
+
+    async fn write_segment(&self, segment: Segment) -> io::Result<()> {
+        self.record(Direction::Sent, &segment);
+        self.inner.write_segment(segment).await
+    }
+
+    async fn close(&self) -> io::Result<()> {
+        let (done, written) = oneshot::channel();
+        let _ = self.records.send(Command::Flush(done));
+        let _ = written.await;
+        self.inner.close().await
+    }
+}
+
+/*
+ * Plays back the received segments of a capture file. Each of them is only handed out once we
+ * have sent as many segments as had been sent before it was received, so the protocols see the
+ * same sequence of events as during the capture. What we send is discarded.
+ */
+pub struct ReplayBearer {
+    /* Received segments along with the number of segments sent before each of them. */
+    segments: tokio::sync::Mutex<std::vec::IntoIter<(usize, Segment)>>,
+    sent: watch::Sender<usize>,
+}
+
+impl ReplayBearer {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut sent = 0;
+        let mut segments = Vec::new();
+        for record in read_capture(path)? {
+            match record.direction {
+                Direction::Received => segments.push((sent, record.segment)),
+                Direction::Sent => sent += 1,
+            }
+        }
+        Ok(ReplayBearer {
+            segments: tokio::sync::Mutex::new(segments.into_iter()),
+            sent: watch::Sender::new(0),
+        })
+    }
+}
+
+#[async_trait]
+impl Bearer for ReplayBearer {
+    async fn read_segment(&self) -> io::Result<Segment> {
+        let (sent, segment) = self.segments.lock().await.next()
+            .ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "end of capture"))?;
+        let mut progress = self.sent.subscribe();
+        progress.wait_for(|count| *count >= sent).await
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "replay closed"))?;
+        Ok(segment)
+    }
+
+    async fn write_segment(&self, segment: Segment) -> io::Result<()> {
+        trace!("replay discarded segment for {:04x}", segment.protocol_id);
+        self.sent.send_modify(|count| *count += 1);
+        Ok(())
+    }
+
+    async fn close(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mux::{memory, Channel},
+        protocols::handshake::HandshakeProtocol,
+    };
+
+    #[tokio::test]
+    async fn captured_handshake_replays() {
+        let path = std::env::temp_dir().join(format!("cardano-ouroboros-network-{}.capture", std::process::id()));
+        let (client_bearer, server_bearer) = memory::pair();
+
+        let (cli, srv) = tokio::join!(
+            async {
+                let client = Channel::new(CaptureBearer::create(client_bearer, &path).unwrap());
+                let handshake = client.handshake(764824073).await;
+                /* Closing waits for the capture to be written. */
+                client.close(Duration::from_secs(1)).await.unwrap();
+                handshake
+            },
+            async {
+                let server = Channel::new(server_bearer);
+                server.execute(HandshakeProtocol::expect(764824073)).await
+            },
+        );
+        cli.unwrap();
+        srv.unwrap();
+
+        let records = read_capture(&path).unwrap();
+        let directions: Vec<_> = records.iter().map(|record| (record.direction, record.segment.protocol_id)).collect();
+        assert_eq!(directions, vec![(Direction::Sent, 0x0000), (Direction::Received, 0x8000)]);
+
+        let replay = Channel::new(ReplayBearer::open(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        replay.handshake(764824073).await.unwrap();
+    }
+}
@@ -0,0 +1,345 @@
+/*
+© 2020 PERLUR Group
+
+SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
+
+*/
+
+use std::{
+    io,
+    io::{Error, ErrorKind},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use tokio::{
+    sync::{mpsc, Mutex},
+    time::sleep_until,
+};
+
+use crate::mux::{Bearer, Segment};
+
+/* Faults injected into the segments sent by one end of a loopback pair. */
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    // Delay before each segment becomes readable by the peer
+    pub latency: Duration,
+    // Split segments further so that no payload exceeds this size
+    pub max_sdu_size: Option<usize>,
+    // Offsets of payload bytes to flip, counted over all payload bytes sent
+    pub corrupt: Vec<usize>,
+}
+
+/* One end of an in-memory connection. */
+pub struct MemoryBearer {
+    faults: Faults,
+    rx: Mutex<mpsc::UnboundedReceiver<(Instant, Segment)>>,
+    tx: Mutex<Option<mpsc::UnboundedSender<(Instant, Segment)>>>,
+    sent: Mutex<usize>,
+}
+
+/* Two bearers connected to each other in memory, no sockets involved. */
+pub fn pair() -> (MemoryBearer, MemoryBearer) {
+    pair_with(Faults::default(), Faults::default())
+}
+
+/* Like pair() but with faults injected into what the client and the server send. */
+pub fn pair_with(client: Faults, server: Faults) -> (MemoryBearer, MemoryBearer) {
+    let (client_tx, server_rx) = mpsc::unbounded_channel();
+    let (server_tx, client_rx) = mpsc::unbounded_channel();
+    (
+        MemoryBearer::new(client, client_rx, client_tx),
+        MemoryBearer::new(server, server_rx, server_tx),
+    )
+}
+
+impl MemoryBearer {
+    fn new(
+        faults: Faults,
+        rx: mpsc::UnboundedReceiver<(Instant, Segment)>,
+        tx: mpsc::UnboundedSender<(Instant, Segment)>,
+    ) -> Self {
+        MemoryBearer {
+            faults,
+            rx: Mutex::new(rx),
+            tx: Mutex::new(Some(tx)),
+            sent: Mutex::new(0),
+        }
+    }
+
+    fn corrupt(&self, payload: &mut [u8], offset: usize) {
+        for position in &self.faults.corrupt {
+            if (offset..offset + payload.len()).contains(position) {
+                payload[position - offset] ^= 0xff;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Bearer for MemoryBearer {
+    async fn read_segment(&self) -> io::Result<Segment> {
+        match self.rx.lock().await.recv().await {
+            Some((deadline, segment)) => {
+                sleep_until(deadline.into()).await;
+                Ok(segment)
+            }
+            None => Err(Error::new(ErrorKind::UnexpectedEof, "peer closed the connection")),
+        }
+    }
+
+    async fn write_segment(&self, segment: Segment) -> io::Result<()> {
+        let tx = self.tx.lock().await;
+        let tx = tx.as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::BrokenPipe, "connection closed"))?;
+        let deadline = Instant::now() + self.faults.latency;
+        let sdu_size = self.faults.max_sdu_size.unwrap_or(usize::MAX).max(1);
+        let mut sent = self.sent.lock().await;
+        for sdu in segment.payload.chunks(sdu_size) {
+            let mut payload = sdu.to_vec();
+            self.corrupt(&mut payload, *sent);
+            *sent += payload.len();
+            let fragment = Segment { payload, ..segment };
+            tx.send((deadline, fragment))
+                .map_err(|_| Error::new(ErrorKind::BrokenPipe, "peer closed the connection"))?;
+        }
+        Ok(())
+    }
+
+    async fn close(&self) -> io::Result<()> {
+        self.tx.lock().await.take();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_cbor::{ser, Value};
+    use crate::{
+        Error,
+        mux::Channel,
+        protocols::{
+            chainsync::ChainSyncProtocol,
+            handshake::{DiffusionMode, Handshake, HandshakeProtocol, Refusal, VersionTable},
+            pingpong::PingPongProtocol,
+        },
+        testing::Scripted,
+    };
+
+    fn responder(id: u16, replies: Vec<Value>) -> Scripted {
+        Scripted::responder(id, replies.iter().map(|reply| ser::to_vec(reply).unwrap()))
+    }
+
+    /* Replies MsgIntersectNotFound and waits for the client to send MsgDone. */
+    fn chainsync_responder() -> Scripted {
+        let tip = Value::Array(vec![
+            Value::Array(vec![Value::Integer(100), Value::Bytes(vec![0xaa; 32])]),
+            Value::Integer(10),
+        ]);
+        Scripted { client_ends: true, ..responder(0x0002, vec![Value::Array(vec![Value::Integer(6), tip])]) }
+    }
+
+    async fn handshake(client: Faults, server: Faults) -> (Result<Handshake, Error>, Result<String, Error>) {
+        let (client_bearer, server_bearer) = pair_with(client, server);
+        let client = Channel::new(client_bearer);
+        let server = Channel::new(server_bearer);
+        tokio::join!(
+            client.handshake(764824073),
+            server.execute(HandshakeProtocol::expect(764824073)),
+        )
+    }
+
+    #[tokio::test]
+    async fn connection_works() {
+        let (cli, srv) = handshake(Faults::default(), Faults::default()).await;
+        let handshake = cli.unwrap();
+        assert_eq!(handshake.version, 14);
+        assert_eq!(handshake.data.network_magic, 764824073);
+        srv.unwrap();
+    }
+
+    #[tokio::test]
+    async fn latency_is_applied_in_both_directions() {
+        let faults = Faults { latency: Duration::from_millis(50), ..Default::default() };
+        let start = Instant::now();
+        let (cli, srv) = handshake(faults.clone(), faults).await;
+        cli.unwrap();
+        srv.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn round_trip_is_measured() {
+        let faults = Faults { latency: Duration::from_millis(20), ..Default::default() };
+        let (client_bearer, server_bearer) = pair_with(faults.clone(), faults);
+        let client = Channel::new(client_bearer);
+        let server = Channel::new(server_bearer);
+        let (cli, srv) = tokio::join!(
+            client.handshake(764824073),
+            server.execute(HandshakeProtocol::expect(764824073)),
+        );
+        cli.unwrap();
+        srv.unwrap();
+
+        let round_trip = client.round_trip();
+        assert_eq!(round_trip.samples, 1);
+        assert!(round_trip.min.unwrap() >= Duration::from_millis(40));
+        assert!(round_trip.clock_offset.is_some());
+        assert_eq!(server.round_trip().samples, 0);
+    }
+
+    #[tokio::test]
+    async fn fragmented_segments_are_reassembled() {
+        let faults = Faults { max_sdu_size: Some(1), ..Default::default() };
+        let (cli, srv) = handshake(faults.clone(), faults).await;
+        cli.unwrap();
+        srv.unwrap();
+    }
+
+    #[tokio::test]
+    async fn versions_are_queried() {
+        let (client_bearer, server_bearer) = pair();
+        let client = Channel::new(client_bearer);
+        let server = Channel::new(server_bearer);
+        let (cli, srv) = tokio::join!(
+            client.query_versions(764824073),
+            server.execute(HandshakeProtocol::expect(VersionTable::from(764824073).restrict(..=13))),
+        );
+        assert_eq!(cli.unwrap().versions().collect::<Vec<_>>(), (1..=13).collect::<Vec<_>>());
+        srv.unwrap();
+
+        let query = VersionTable::from(764824073).with_query();
+        assert!(matches!(client.handshake(query).await, Err(Error::InvalidInput(_))));
+    }
+
+    #[tokio::test]
+    async fn corrupted_magic_is_refused() {
+        /* The accept message starts with 83 01 0e 84 1a followed by the network magic. */
+        let faults = Faults { corrupt: vec![5], ..Default::default() };
+        let (cli, srv) = handshake(Faults::default(), faults).await;
+        match cli {
+            Err(Error::HandshakeRefused(Refusal::Refused(14, message))) => assert!(message.contains("network magic")),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        srv.unwrap();
+    }
+
+    #[tokio::test]
+    async fn chainsync_runs_in_memory() {
+        /* Slow enough for the client to be closing by the time the server replies. */
+        let faults = Faults { latency: Duration::from_millis(20), ..Default::default() };
+        let (client_bearer, server_bearer) = pair_with(faults.clone(), faults);
+        let client = Channel::new(client_bearer);
+        let server = Channel::new(server_bearer);
+        let chainsync = client.register(ChainSyncProtocol::default()).unwrap();
+        let (cli, srv, closed) = tokio::join!(
+            chainsync.run(),
+            server.execute(chainsync_responder()),
+            async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                client.close(Duration::from_secs(1)).await
+            },
+        );
+        assert_eq!(cli.unwrap(), "Done");
+        srv.unwrap();
+        closed.unwrap();
+
+        let metrics = server.metrics();
+        let chainsync = &metrics.protocols[&0x8002];
+        assert_eq!(chainsync.messages_received, vec![(4, 1), (7, 1)].into_iter().collect());
+        assert_eq!(chainsync.messages_sent, vec![(6, 1)].into_iter().collect());
+        assert_eq!(chainsync.segments_received, 2);
+        assert_eq!(metrics.bytes_received, chainsync.bytes_received);
+    }
+
+    #[tokio::test]
+    async fn duplex_runs_both_roles() {
+        let faults = Faults { latency: Duration::from_millis(20), ..Default::default() };
+        let (client_bearer, server_bearer) = pair_with(faults.clone(), faults);
+        let client = Channel::new(client_bearer);
+        let server = Channel::new(server_bearer);
+        let duplex = DiffusionMode::InitiatorAndResponder;
+        let (cli, srv) = tokio::join!(
+            client.register(HandshakeProtocol::new(764824073).with_diffusion_mode(duplex)).unwrap().finish(),
+            server.register(HandshakeProtocol::expect(764824073).with_diffusion_mode(duplex)).unwrap().finish(),
+        );
+        assert_eq!(cli.unwrap().diffusion_mode(), Some(duplex));
+        assert_eq!(srv.unwrap().diffusion_mode(), Some(duplex));
+
+        /* Both ends run the chainsync client and its responder on subchannel 2. */
+        let subchannels = |channel: &Channel| (
+            channel.register(ChainSyncProtocol::default()).unwrap(),
+            channel.register(chainsync_responder()).unwrap(),
+        );
+        let (cli_initiator, cli_responder) = subchannels(&client);
+        let (srv_initiator, srv_responder) = subchannels(&server);
+        let close = |channel: Channel| async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            channel.close(Duration::from_secs(1)).await
+        };
+        let (cli_initiator, cli_responder, srv_initiator, srv_responder, cli_closed, srv_closed) = tokio::join!(
+            cli_initiator.run(),
+            cli_responder.run(),
+            srv_initiator.run(),
+            srv_responder.run(),
+            close(client),
+            close(server),
+        );
+        assert_eq!(cli_initiator.unwrap(), "Done");
+        assert_eq!(srv_initiator.unwrap(), "Done");
+        cli_responder.unwrap();
+        srv_responder.unwrap();
+        cli_closed.unwrap();
+        srv_closed.unwrap();
+    }
+
+    #[tokio::test]
+    async fn close_terminates_protocols() {
+        let (client_bearer, server_bearer) = pair();
+        let client = Channel::new(client_bearer);
+        let server = Channel::new(server_bearer);
+        let pinger = client.register(PingPongProtocol::new(0x0008)).unwrap();
+        let (cli, srv, closed) = tokio::join!(
+            pinger.run(),
+            server.execute(PingPongProtocol::expect(0x0008)),
+            async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                client.close(Duration::from_secs(1)).await
+            },
+        );
+        cli.unwrap();
+        srv.unwrap();
+        closed.unwrap();
+    }
+
+    #[tokio::test]
+    async fn close_drops_unresponsive_connections() {
+        let (client_bearer, _server_bearer) = pair();
+        let client = Channel::new(client_bearer);
+        /* Registered but never driven, so it never terminates. */
+        let _stuck = client.register(PingPongProtocol::new(0x0008)).unwrap();
+        let start = Instant::now();
+        assert!(matches!(client.close(Duration::from_millis(50)).await, Err(Error::Timeout(_))));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn malformed_message_tears_down_only_its_connection() {
+        let (client_bearer, server_bearer) = pair();
+        let client = Channel::new(client_bearer);
+        let server = Channel::new(server_bearer);
+        let (cli, srv) = tokio::join!(
+            client.execute(ChainSyncProtocol::default()),
+            server.execute(responder(0x0002, vec![Value::Text("garbage".to_string())])),
+        );
+        assert!(matches!(cli, Err(Error::ProtocolViolation(_))));
+        srv.unwrap();
+        assert!(matches!(client.handshake(764824073).await, Err(Error::ProtocolViolation(_))));
+
+        /* Other connections carry on as usual. */
+        let (cli, srv) = handshake(Faults::default(), Faults::default()).await;
+        cli.unwrap();
+        srv.unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+/*
+© 2020 PERLUR Group
+
+SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
+
+*/
+
+use std::collections::BTreeMap;
+
+use crate::mux::capture::Direction;
+
+/* Traffic counters of a connection, see Channel::metrics(). */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    pub segments_sent: u64,
+    pub segments_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    // Counters of each subchannel by protocol id, mode bit included
+    pub protocols: BTreeMap<u16, ProtocolMetrics>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProtocolMetrics {
+    pub segments_sent: u64,
+    pub segments_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    // Messages by message id, the first item of the message
+    pub messages_sent: BTreeMap<u64, u64>,
+    pub messages_received: BTreeMap<u64, u64>,
+    // Messages by the state before and after them
+    pub transitions: BTreeMap<(String, String), u64>,
+}
+
+impl Metrics {
+    pub(crate) fn segment(&mut self, id: u16, direction: Direction, length: usize) {
+        let protocol = self.protocols.entry(id).or_default();
+        match direction {
+            Direction::Sent => {
+                self.segments_sent += 1;
+                self.bytes_sent += length as u64;
+                protocol.segments_sent += 1;
+                protocol.bytes_sent += length as u64;
+            }
+            Direction::Received => {
+                self.segments_received += 1;
+                self.bytes_received += length as u64;
+                protocol.segments_received += 1;
+                protocol.bytes_received += length as u64;
+            }
+        }
+    }
+
+    pub(crate) fn message(&mut self, id: u16, direction: Direction, message_id: Option<u64>, from: String, to: String) {
+        let protocol = self.protocols.entry(id).or_default();
+        if let Some(message_id) = message_id {
+            let messages = match direction {
+                Direction::Sent => &mut protocol.messages_sent,
+                Direction::Received => &mut protocol.messages_received,
+            };
+            *messages.entry(message_id).or_default() += 1;
+        }
+        *protocol.transitions.entry((from, to)).or_default() += 1;
+    }
+}
+
+/*
+ * Mini-protocol messages are CBOR arrays starting with a small integer identifying the message,
+ * only the head of the message is looked at so this stays cheap for large ones.
+ */
+pub(crate) fn message_id(message: &[u8]) -> Option<u64> {
+    let items = match *message.first()? {
+        0x80..=0x97 | 0x9f => message.get(1..)?,
+        0x98 => message.get(2..)?,
+        0x99 => message.get(3..)?,
+        0x9a => message.get(5..)?,
+        0x9b => message.get(9..)?,
+        _ => return None,
+    };
+    match *items.first()? {
+        id @ 0x00..=0x17 => Some(id as u64),
+        0x18 => items.get(1).map(|id| *id as u64),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_ids_are_found() {
+        assert_eq!(message_id(&[0x81, 0x07]), Some(7));
+        assert_eq!(message_id(&[0x83, 0x18, 0x20, 0x01, 0x02]), Some(32));
+        assert_eq!(message_id(&[0x9f, 0x01, 0xff]), Some(1));
+        assert_eq!(message_id(&[0x40]), None);
+        assert_eq!(message_id(&[0x82]), None);
+    }
+}
@@ -0,0 +1,111 @@
+/*
+© 2020 PERLUR Group
+
+SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
+
+*/
+
+use std::time::Duration;
+
+/* Round trip and peer clock estimates of a connection, see Channel::round_trip(). */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoundTrip {
+    // Number of request/response pairs measured
+    pub samples: u64,
+    pub latest: Option<Duration>,
+    pub min: Option<Duration>,
+    // Smoothed the way TCP does it
+    pub smoothed: Option<Duration>,
+    // Peer mux clock minus ours in microseconds, taken from the sample with the lowest round trip
+    pub clock_offset: Option<i64>,
+    // How much faster the peer mux clock runs than ours, in parts per million
+    pub clock_skew: Option<f64>,
+}
+
+/*
+ * Each request we send is timed until the first segment of the response arrives. The peer
+ * stamps that segment with its own clock, assuming it did so halfway through the round trip
+ * tells us how far apart the two clocks are.
+ */
+#[derive(Debug, Default)]
+pub(crate) struct Estimator {
+    round_trip: RoundTrip,
+    /* Local time and clock offset of the first sample, to see the offset drift. */
+    first_offset: Option<(u64, i64)>,
+    /* Peer timestamps are 32 bit microseconds and wrap about every 71 minutes. */
+    peer_last: u32,
+    peer_wraps: u64,
+}
+
+impl Estimator {
+    /* Extend a peer timestamp to 64 bits, must see every received segment in order. */
+    pub(crate) fn peer_time(&mut self, timestamp: u32) -> u64 {
+        if timestamp < self.peer_last {
+            self.peer_wraps += 1;
+        }
+        self.peer_last = timestamp;
+        (self.peer_wraps << 32) + timestamp as u64
+    }
+
+    /* A response stamped with peer_time arrived at received for a request written at sent. */
+    pub(crate) fn sample(&mut self, sent: Duration, received: Duration, peer_time: u64) {
+        let rtt = received.saturating_sub(sent);
+        let midpoint = ((sent + received) / 2).as_micros() as i64;
+        let offset = peer_time as i64 - midpoint;
+        let round_trip = &mut self.round_trip;
+
+        round_trip.samples += 1;
+        round_trip.latest = Some(rtt);
+        round_trip.smoothed = Some(match round_trip.smoothed {
+            Some(smoothed) => (smoothed * 7 + rtt) / 8,
+            None => rtt,
+        });
+        if !matches!(round_trip.min, Some(min) if rtt > min) {
+            round_trip.min = Some(rtt);
+            round_trip.clock_offset = Some(offset);
+        }
+
+        let now = received.as_micros() as u64;
+        match self.first_offset {
+            Some((first, first_offset)) if now > first => {
+                round_trip.clock_skew = Some((offset - first_offset) as f64 * 1e6 / (now - first) as f64);
+            }
+            Some(_) => {}
+            None => self.first_offset = Some((now, offset)),
+        }
+    }
+
+    pub(crate) fn round_trip(&self) -> RoundTrip {
+        self.round_trip.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_are_combined() {
+        let mut estimator = Estimator::default();
+        /* The peer clock is 5s ahead and runs 100ppm fast. */
+        let peer_time = estimator.peer_time(5_000_000 + 2_000);
+        estimator.sample(Duration::from_millis(1), Duration::from_millis(3), peer_time);
+        let peer_time = estimator.peer_time(10_002_000 + 5_001_000);
+        estimator.sample(Duration::from_millis(10_000), Duration::from_millis(10_004), peer_time);
+
+        let round_trip = estimator.round_trip();
+        assert_eq!(round_trip.samples, 2);
+        assert_eq!(round_trip.latest, Some(Duration::from_millis(4)));
+        assert_eq!(round_trip.min, Some(Duration::from_millis(2)));
+        assert_eq!(round_trip.smoothed, Some(Duration::from_micros(2250)));
+        assert_eq!(round_trip.clock_offset, Some(5_000_000));
+        assert!((round_trip.clock_skew.unwrap() - 100.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn peer_time_wraps() {
+        let mut estimator = Estimator::default();
+        assert_eq!(estimator.peer_time(u32::MAX), u32::MAX as u64);
+        assert_eq!(estimator.peer_time(1), (1 << 32) + 1);
+    }
+}
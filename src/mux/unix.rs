@@ -0,0 +1,91 @@
+/*
+© 2020 PERLUR Group
+
+SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
+
+*/
+
+use std::path::Path;
+
+use tokio::net::{
+    unix::{OwnedReadHalf, OwnedWriteHalf},
+    UnixStream,
+};
+
+use crate::{
+    Error,
+    mux::StreamBearer,
+};
+pub use crate::mux::Channel;
+
+pub type UnixBearer = StreamBearer<OwnedReadHalf, OwnedWriteHalf>;
+
+impl From<UnixStream> for UnixBearer {
+    fn from(stream: UnixStream) -> Self {
+        let (reader, writer) = stream.into_split();
+        StreamBearer::new(reader, writer)
+    }
+}
+
+/* Connect to a local node, typically through its `node.socket`, to run node-to-client protocols. */
+pub async fn connect(path: impl AsRef<Path>) -> Result<Channel, Error> {
+    Ok(Channel::new(connect_bearer(path).await?))
+}
+
+/* Like connect() but leaves it to the caller to wrap the bearer, e.g. in a CaptureBearer. */
+pub async fn connect_bearer(path: impl AsRef<Path>) -> Result<UnixBearer, Error> {
+    let stream = UnixStream::connect(path).await?;
+
+    Ok(UnixBearer::from(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::handshake::{HandshakeProtocol, VersionTable};
+    use tokio::net::UnixListener;
+
+    #[tokio::test]
+    async fn connection_works() {
+        let path = std::env::temp_dir().join(format!("cardano-ouroboros-network-{}.socket", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let (srv, cli) = tokio::join!(
+            async {
+                let server = Channel::new(UnixBearer::from(listener.accept().await.unwrap().0));
+                server.execute(HandshakeProtocol::expect(764824073)).await
+            },
+            async {
+                let client = connect(&path).await.unwrap();
+                client.handshake(764824073).await
+            },
+        );
+        std::fs::remove_file(&path).unwrap();
+        srv.unwrap();
+        cli.unwrap();
+    }
+
+    #[tokio::test]
+    async fn node_to_client_handshake_works() {
+        let path = std::env::temp_dir().join(format!("cardano-ouroboros-network-n2c-{}.socket", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let (srv, cli) = tokio::join!(
+            async {
+                let server = Channel::new(UnixBearer::from(listener.accept().await.unwrap().0));
+                server.execute(HandshakeProtocol::expect(VersionTable::node_to_client(764824073))).await
+            },
+            async {
+                let client = connect(&path).await.unwrap();
+                client.handshake(VersionTable::node_to_client(764824073)).await
+            },
+        );
+        std::fs::remove_file(&path).unwrap();
+        srv.unwrap();
+        let handshake = cli.unwrap();
+        assert_eq!(handshake.version, 0x8010);
+        assert_eq!(handshake.data.network_magic, 764824073);
+    }
+}
@@ -1,4 +1,4 @@
-/**
+/*
 Forked-off from https://github.com/AndrewWestberg/cncli/ on 2020-11-30
 © 2020 Andrew Westberg licensed under Apache-2.0
 
@@ -20,6 +20,7 @@ use serde_cbor::{de, Deserializer, ser, Value};
 
 use crate::{
     Agency,
+    Error,
     Protocol,
     BlockStore,
     BlockHeader,
@@ -49,7 +50,7 @@ pub struct Tip {
     pub hash: Vec<u8>,
 }
 
-pub trait Listener {
+pub trait Listener: Send {
     fn handle_tip(&mut self, msg_roll_forward: &BlockHeader);
 }
 
@@ -61,7 +62,7 @@ pub struct ChainSyncProtocol {
     pub network_magic: u32,
     pub pending_blocks: Vec<BlockHeader>,
     pub state: State,
-    pub result: Option<Result<String, String>>,
+    pub result: Option<Result<String, Error>>,
     pub is_intersect_found: bool,
     pub tip_to_intersect: Option<Tip>,
     pub notify: Option<Box<dyn Listener>>,
@@ -87,27 +88,29 @@ impl Default for ChainSyncProtocol {
 
 impl ChainSyncProtocol {
     const FIVE_SECS: Duration = Duration::from_secs(5);
+    // State timeouts from the network spec, MustReply uses the upper bound of its random range
+    const INTERSECT_TIMEOUT: Duration = Duration::from_secs(10);
+    const CAN_AWAIT_TIMEOUT: Duration = Duration::from_secs(10);
+    const MUST_REPLY_TIMEOUT: Duration = Duration::from_secs(269);
+    // Ingress queue limit of the reference implementation
+    const INGRESS_LIMIT: usize = 462000;
 
     fn save_block(&mut self, msg_roll_forward: &BlockHeader, is_tip: bool) -> io::Result<()> {
-        match self.store.as_mut() {
-            Some(store) => {
-                self.pending_blocks.push((*msg_roll_forward).clone());
+        if let Some(store) = self.store.as_mut() {
+            self.pending_blocks.push((*msg_roll_forward).clone());
 
-                if is_tip || self.last_insert_time.elapsed() > ChainSyncProtocol::FIVE_SECS {
-                    store.save_block(&mut self.pending_blocks, self.network_magic)?;
-                    self.last_insert_time = Instant::now();
-                }
+            if is_tip || self.last_insert_time.elapsed() > ChainSyncProtocol::FIVE_SECS {
+                store.save_block(&mut self.pending_blocks, self.network_magic)?;
+                self.last_insert_time = Instant::now();
             }
-            None => {}
         }
 
         Ok(())
     }
 
     fn notify_tip(&mut self, msg_roll_forward: &BlockHeader) {
-        match &mut self.notify {
-            Some(listener) => listener.handle_tip(msg_roll_forward),
-            None => {}
+        if let Some(listener) = &mut self.notify {
+            listener.handle_tip(msg_roll_forward);
         }
     }
 
@@ -134,15 +137,19 @@ impl ChainSyncProtocol {
         // we just send an array containing the message_id for this one.
         ser::to_vec_packed(&Value::Array(vec![Value::Integer(0)])).unwrap()
     }
+
+    fn msg_done(&self) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(7)])).unwrap()
+    }
 }
 
 impl Protocol for ChainSyncProtocol {
     fn protocol_id(&self) -> u16 {
-        return 0x0002u16;
+        0x0002u16
     }
 
-    fn result(&self) -> Result<String, String> {
-        self.result.clone().unwrap()
+    fn result(&self) -> Result<String, Error> {
+        self.result.clone().unwrap_or_else(|| Err(Error::ProtocolViolation("no result".to_string())))
     }
 
     fn role(&self) -> Agency {
@@ -150,43 +157,52 @@ impl Protocol for ChainSyncProtocol {
     }
 
     fn agency(&self) -> Agency {
-        return match self.state {
+        match self.state {
             State::Idle => { Agency::Client }
             State::Intersect => { Agency::Server }
             State::CanAwait => { Agency::Server }
             State::MustReply => { Agency::Server }
             State::Done => { Agency::None }
-        };
+        }
     }
 
     fn state(&self) -> String {
         format!("{:?}", self.state)
     }
 
+    fn timeout(&self) -> Option<Duration> {
+        match self.state {
+            State::Intersect => Some(ChainSyncProtocol::INTERSECT_TIMEOUT),
+            State::CanAwait => Some(ChainSyncProtocol::CAN_AWAIT_TIMEOUT),
+            State::MustReply => Some(ChainSyncProtocol::MUST_REPLY_TIMEOUT),
+            State::Idle | State::Done => None,
+        }
+    }
+
+    fn ingress_limit(&self) -> usize {
+        ChainSyncProtocol::INGRESS_LIMIT
+    }
+
     fn send_data(&mut self) -> Option<Vec<u8>> {
-        return match self.state {
+        match self.state {
             State::Idle => {
                 trace!("ChainSyncProtocol::State::Idle");
                 if !self.is_intersect_found {
                     let mut chain_blocks: Vec<(i64, Vec<u8>)> = vec![];
 
                     /* Classic sync: Use blocks from store if available. */
-                    match self.store.as_mut() {
-                        Some(store) => {
-                            let blocks = (*store).load_blocks()?;
-                            for (i, block) in blocks.iter().enumerate() {
-                                // all powers of 2 including 0th element 0, 2, 4, 8, 16, 32
-                                if (i == 0) || ((i > 1) && (i & (i - 1) == 0)) {
-                                    chain_blocks.push(block.clone());
-                                }
+                    if let Some(store) = self.store.as_mut() {
+                        let blocks = (*store).load_blocks()?;
+                        for (i, block) in blocks.iter().enumerate() {
+                            // all powers of 2 including 0th element 0, 2, 4, 8, 16, 32
+                            if (i == 0) || ((i > 1) && (i & (i - 1) == 0)) {
+                                chain_blocks.push(block.clone());
                             }
                         }
-                        None => {}
                     }
 
                     /* Tip discovery: Use discovered tip to retrieve header. */
-                    if self.tip_to_intersect.is_some() {
-                        let tip = self.tip_to_intersect.as_ref().unwrap();
+                    if let Some(tip) = self.tip_to_intersect.as_ref() {
                         chain_blocks.push((tip.slot_number, tip.hash.clone()));
                     }
 
@@ -226,10 +242,22 @@ impl Protocol for ChainSyncProtocol {
                 debug!("ChainSyncProtocol::State::Done");
                 None
             }
-        };
+        }
+    }
+
+    fn terminate(&mut self) -> Option<Vec<u8>> {
+        match self.state {
+            State::Idle => {
+                debug!("msg_done");
+                self.state = State::Done;
+                self.result = Some(Ok(String::from("Done")));
+                Some(self.msg_done())
+            }
+            _ => None,
+        }
     }
 
-    fn receive_data(&mut self, data: Vec<u8>) {
+    fn receive_data(&mut self, data: Vec<u8>) -> Result<(), Error> {
         //msgRequestNext         = [0]
         //msgAwaitReply          = [1]
         //msgRollForward         = [2, wrappedHeader, tip]
@@ -242,118 +270,124 @@ impl Protocol for ChainSyncProtocol {
         let cbor_iter = Deserializer::from_slice(&data[..]).into_iter::<Value>();
 
         for cbor_result in cbor_iter {
-            match cbor_result {
-                Ok(cbor_value) => {
-                    match cbor_value {
-                        Value::Array(cbor_array) => {
-                            match cbor_array[0] {
-                                Value::Integer(message_id) => {
-                                    match message_id {
-                                        1 => {
-                                            // Server wants us to wait a bit until it gets a new block
-                                            self.state = State::MustReply;
-                                        }
-                                        2 => {
-                                            // MsgRollForward
-                                            match parse_msg_roll_forward(cbor_array) {
-                                                None => { warn!("Probably a byron block. skipping...") }
-                                                Some((msg_roll_forward, tip)) => {
-                                                    let is_tip = msg_roll_forward.slot_number == tip.slot_number && msg_roll_forward.hash == tip.hash;
-                                                    trace!("block {} of {}, {:.2}% synced", msg_roll_forward.block_number, tip.block_number, (msg_roll_forward.block_number as f64 / tip.block_number as f64) * 100.0);
-                                                    if is_tip || self.last_log_time.elapsed() > ChainSyncProtocol::FIVE_SECS {
-                                                        if self.mode == Mode::Sync {
-                                                            info!("block {} of {}, {:.2}% synced", msg_roll_forward.block_number, tip.block_number, (msg_roll_forward.block_number as f64 / tip.block_number as f64) * 100.0);
-                                                        }
-                                                        self.last_log_time = Instant::now()
-                                                    }
-
-                                                    /* Classic sync: Store header data. */
-                                                    /* TODO: error handling */
-                                                    let _ = self.save_block(&msg_roll_forward, is_tip);
-
-                                                    if is_tip {
-                                                        /* Got complete tip header. */
-                                                        self.notify_tip(&msg_roll_forward);
-                                                    } else {
-                                                        match self.mode {
-                                                            /* Next time get tip header. */
-                                                            Mode::SendTip => self.jump_to_tip(tip),
-                                                            _ => {}
-                                                        }
-                                                    }
-                                                }
-                                            }
-
-                                            self.state = State::Idle;
-
-                                            // testing only so we sync only a single block
-                                            // self.state = State::Done;
-                                            // self.result = Some(Ok(String::from("Done")))
-                                        }
-                                        3 => {
-                                            // MsgRollBackward
-                                            let slot = parse_msg_roll_backward(cbor_array);
-                                            warn!("rollback to slot: {}", slot);
-                                            self.state = State::Idle;
-                                        }
-                                        5 => {
-                                            debug!("MsgIntersectFound: {:?}", cbor_array);
-                                            self.is_intersect_found = true;
-                                            self.state = State::Idle;
-                                        }
-                                        6 => {
-                                            warn!("MsgIntersectNotFound: {:?}", cbor_array);
-                                            self.is_intersect_found = true; // should start syncing at first byron block. We will just skip all byron blocks.
-                                            self.state = State::Idle;
-                                        }
-                                        7 => {
-                                            warn!("MsgDone: {:?}", cbor_array);
-                                            self.state = State::Done;
-                                            self.result = Some(Ok(String::from("Done")))
-                                        }
-                                        _ => {
-                                            error!("Got unexpected message_id: {}", message_id);
-                                        }
-                                    }
+            let cbor_array = match cbor_result? {
+                Value::Array(cbor_array) => cbor_array,
+                cbor_value => {
+                    return Err(Error::ProtocolViolation(format!("Unexpected cbor! {:?}", cbor_value)));
+                }
+            };
+            let message_id = item(&cbor_array, 0)?.integer()?;
+            if !expected(&self.state, message_id) {
+                return Err(Error::ProtocolViolation(format!(
+                    "unexpected message_id {} in state {:?}", message_id, self.state,
+                )));
+            }
+            match message_id {
+                1 => {
+                    // Server wants us to wait a bit until it gets a new block
+                    self.state = State::MustReply;
+                }
+                2 => {
+                    // MsgRollForward
+                    match parse_msg_roll_forward(cbor_array)? {
+                        None => { warn!("Probably a byron block. skipping...") }
+                        Some((msg_roll_forward, tip)) => {
+                            let is_tip = msg_roll_forward.slot_number == tip.slot_number && msg_roll_forward.hash == tip.hash;
+                            trace!("block {} of {}, {:.2}% synced", msg_roll_forward.block_number, tip.block_number, (msg_roll_forward.block_number as f64 / tip.block_number as f64) * 100.0);
+                            if is_tip || self.last_log_time.elapsed() > ChainSyncProtocol::FIVE_SECS {
+                                if self.mode == Mode::Sync {
+                                    info!("block {} of {}, {:.2}% synced", msg_roll_forward.block_number, tip.block_number, (msg_roll_forward.block_number as f64 / tip.block_number as f64) * 100.0);
                                 }
-                                _ => {
-                                    error!("Unexpected cbor!")
+                                self.last_log_time = Instant::now()
+                            }
+
+                            /* Classic sync: Store header data. */
+                            /* TODO: error handling */
+                            let _ = self.save_block(&msg_roll_forward, is_tip);
+
+                            if is_tip {
+                                /* Got complete tip header. */
+                                self.notify_tip(&msg_roll_forward);
+                            } else {
+                                /* Next time get tip header. */
+                                if self.mode == Mode::SendTip {
+                                    self.jump_to_tip(tip);
                                 }
                             }
                         }
-                        _ => {
-                            error!("Unexpected cbor!")
-                        }
                     }
+
+                    self.state = State::Idle;
+
+                    // testing only so we sync only a single block
+                    // self.state = State::Done;
+                    // self.result = Some(Ok(String::from("Done")))
+                }
+                3 => {
+                    // MsgRollBackward
+                    let slot = parse_msg_roll_backward(cbor_array);
+                    warn!("rollback to slot: {}", slot);
+                    self.state = State::Idle;
+                }
+                5 => {
+                    debug!("MsgIntersectFound: {:?}", cbor_array);
+                    self.is_intersect_found = true;
+                    self.state = State::Idle;
+                }
+                6 => {
+                    warn!("MsgIntersectNotFound: {:?}", cbor_array);
+                    self.is_intersect_found = true; // should start syncing at first byron block. We will just skip all byron blocks.
+                    self.state = State::Idle;
+                }
+                message_id => {
+                    return Err(Error::ProtocolViolation(format!("Got unexpected message_id: {}", message_id)));
                 }
-                Err(err) => { error!("cbor decode error!: {}, hex: {}", err, hex::encode(&data)) }
             }
         }
+
+        Ok(())
     }
 }
 
+/* Whether the server may send the message in the state, only the client ever sends MsgDone. */
+fn expected(state: &State, message_id: i128) -> bool {
+    matches!(
+        (state, message_id),
+        (State::CanAwait, 1)
+            | (State::CanAwait | State::MustReply, 2 | 3)
+            | (State::Intersect, 5 | 6)
+    )
+}
+
+/* Fetch an item of a cbor array that a well-formed message always has. */
+fn item(array: &[Value], index: usize) -> Result<&Value, Error> {
+    array.get(index)
+        .ok_or_else(|| Error::Decode(format!("missing item {} in {:?}", index, array)))
+}
+
 trait UnwrapValue {
-    fn integer(&self) -> i128;
-    fn bytes(&self) -> Vec<u8>;
+    fn integer(&self) -> Result<i128, Error>;
+    fn bytes(&self) -> Result<Vec<u8>, Error>;
 }
 
 impl UnwrapValue for Value {
-    fn integer(&self) -> i128 {
+    fn integer(&self) -> Result<i128, Error> {
         match self {
-            Value::Integer(integer_value) => { *integer_value }
-            _ => { panic!("not an integer!") }
+            Value::Integer(integer_value) => { Ok(*integer_value) }
+            _ => { Err(Error::Decode(format!("not an integer! {:?}", self))) }
         }
     }
 
-    fn bytes(&self) -> Vec<u8> {
+    fn bytes(&self) -> Result<Vec<u8>, Error> {
         match self {
-            Value::Bytes(bytes_vec) => { bytes_vec.clone() }
-            _ => { panic!("not a byte array!") }
+            Value::Bytes(bytes_vec) => { Ok(bytes_vec.clone()) }
+            _ => { Err(Error::Decode(format!("not a byte array! {:?}", self))) }
         }
     }
 }
 
-pub fn parse_msg_roll_forward(cbor_array: Vec<Value>) -> Option<(BlockHeader, Tip)> {
+/* Returns None for headers we do not understand, such as byron ones, and an error for malformed ones. */
+pub fn parse_msg_roll_forward(cbor_array: Vec<Value>) -> Result<Option<(BlockHeader, Tip)>, Error> {
     let mut msg_roll_forward = BlockHeader {
         block_number: 0,
         slot_number: 0,
@@ -380,105 +414,105 @@ pub fn parse_msg_roll_forward(cbor_array: Vec<Value>) -> Option<(BlockHeader, Ti
         hash: vec![],
     };
 
-    match &cbor_array[1] {
+    match item(&cbor_array, 1)? {
         Value::Array(header_array) => {
-            match &header_array[1] {
+            match item(header_array, 1)? {
                 Value::Bytes(wrapped_block_header_bytes) => {
                     // calculate the block hash
-                    let hash = Params::new().hash_length(32).to_state().update(&*wrapped_block_header_bytes).finalize();
+                    let hash = Params::new().hash_length(32).to_state().update(wrapped_block_header_bytes).finalize();
                     msg_roll_forward.hash = hash.as_bytes().to_owned();
 
-                    let block_header: Value = de::from_slice(&wrapped_block_header_bytes[..]).unwrap();
+                    let block_header: Value = de::from_slice(&wrapped_block_header_bytes[..])?;
                     match block_header {
                         Value::Array(block_header_array) => {
-                            match &block_header_array[0] {
+                            match item(&block_header_array, 0)? {
                                 Value::Array(block_header_array_inner) => {
-                                    msg_roll_forward.block_number = block_header_array_inner[0].integer() as i64;
-                                    msg_roll_forward.slot_number = block_header_array_inner[1].integer() as i64;
-                                    msg_roll_forward.prev_hash.append(&mut block_header_array_inner[2].bytes());
-                                    msg_roll_forward.node_vkey.append(&mut block_header_array_inner[3].bytes());
-                                    msg_roll_forward.node_vrf_vkey.append(&mut block_header_array_inner[4].bytes());
-                                    match &block_header_array_inner[5] {
+                                    msg_roll_forward.block_number = item(block_header_array_inner, 0)?.integer()? as i64;
+                                    msg_roll_forward.slot_number = item(block_header_array_inner, 1)?.integer()? as i64;
+                                    msg_roll_forward.prev_hash.append(&mut item(block_header_array_inner, 2)?.bytes()?);
+                                    msg_roll_forward.node_vkey.append(&mut item(block_header_array_inner, 3)?.bytes()?);
+                                    msg_roll_forward.node_vrf_vkey.append(&mut item(block_header_array_inner, 4)?.bytes()?);
+                                    match item(block_header_array_inner, 5)? {
                                         Value::Array(nonce_array) => {
-                                            msg_roll_forward.eta_vrf_0.append(&mut nonce_array[0].bytes());
-                                            msg_roll_forward.eta_vrf_1.append(&mut nonce_array[1].bytes());
+                                            msg_roll_forward.eta_vrf_0.append(&mut item(nonce_array, 0)?.bytes()?);
+                                            msg_roll_forward.eta_vrf_1.append(&mut item(nonce_array, 1)?.bytes()?);
                                         }
                                         _ => {
                                             warn!("invalid cbor! code: 340");
-                                            return None;
+                                            return Ok(None);
                                         }
                                     }
-                                    match &block_header_array_inner[6] {
+                                    match item(block_header_array_inner, 6)? {
                                         Value::Array(leader_array) => {
-                                            msg_roll_forward.leader_vrf_0.append(&mut leader_array[0].bytes());
-                                            msg_roll_forward.leader_vrf_1.append(&mut leader_array[1].bytes());
+                                            msg_roll_forward.leader_vrf_0.append(&mut item(leader_array, 0)?.bytes()?);
+                                            msg_roll_forward.leader_vrf_1.append(&mut item(leader_array, 1)?.bytes()?);
                                         }
                                         _ => {
                                             warn!("invalid cbor! code: 341");
-                                            return None;
+                                            return Ok(None);
                                         }
                                     }
-                                    msg_roll_forward.block_size = block_header_array_inner[7].integer() as i64;
-                                    msg_roll_forward.block_body_hash.append(&mut block_header_array_inner[8].bytes());
-                                    msg_roll_forward.pool_opcert.append(&mut block_header_array_inner[9].bytes());
-                                    msg_roll_forward.unknown_0 = block_header_array_inner[10].integer() as i64;
-                                    msg_roll_forward.unknown_1 = block_header_array_inner[11].integer() as i64;
-                                    msg_roll_forward.unknown_2.append(&mut block_header_array_inner[12].bytes());
-                                    msg_roll_forward.protocol_major_version = block_header_array_inner[13].integer() as i64;
-                                    msg_roll_forward.protocol_minor_version = block_header_array_inner[14].integer() as i64;
+                                    msg_roll_forward.block_size = item(block_header_array_inner, 7)?.integer()? as i64;
+                                    msg_roll_forward.block_body_hash.append(&mut item(block_header_array_inner, 8)?.bytes()?);
+                                    msg_roll_forward.pool_opcert.append(&mut item(block_header_array_inner, 9)?.bytes()?);
+                                    msg_roll_forward.unknown_0 = item(block_header_array_inner, 10)?.integer()? as i64;
+                                    msg_roll_forward.unknown_1 = item(block_header_array_inner, 11)?.integer()? as i64;
+                                    msg_roll_forward.unknown_2.append(&mut item(block_header_array_inner, 12)?.bytes()?);
+                                    msg_roll_forward.protocol_major_version = item(block_header_array_inner, 13)?.integer()? as i64;
+                                    msg_roll_forward.protocol_minor_version = item(block_header_array_inner, 14)?.integer()? as i64;
                                 }
                                 _ => {
                                     warn!("invalid cbor! code: 342");
-                                    return None;
+                                    return Ok(None);
                                 }
                             }
                         }
                         _ => {
                             warn!("invalid cbor! code: 343");
-                            return None;
+                            return Ok(None);
                         }
                     }
                 }
                 _ => {
                     warn!("invalid cbor! code: 344");
-                    return None;
+                    return Ok(None);
                 }
             }
         }
         _ => {
             warn!("invalid cbor! code: 345");
-            return None;
+            return Ok(None);
         }
     }
 
-    match &cbor_array[2] {
+    match item(&cbor_array, 2)? {
         Value::Array(tip_array) => {
-            match &tip_array[0] {
+            match item(tip_array, 0)? {
                 Value::Array(tip_info_array) => {
-                    tip.slot_number = tip_info_array[0].integer() as i64;
-                    tip.hash.append(&mut tip_info_array[1].bytes());
+                    tip.slot_number = item(tip_info_array, 0)?.integer()? as i64;
+                    tip.hash.append(&mut item(tip_info_array, 1)?.bytes()?);
                 }
                 _ => {
                     warn!("invalid cbor! code: 346");
-                    return None;
+                    return Ok(None);
                 }
             }
-            tip.block_number = tip_array[1].integer() as i64;
+            tip.block_number = item(tip_array, 1)?.integer()? as i64;
         }
         _ => {
             warn!("invalid cbor! code: 347");
-            return None;
+            return Ok(None);
         }
     }
 
-    Some((msg_roll_forward, tip))
+    Ok(Some((msg_roll_forward, tip)))
 }
 
 pub fn parse_msg_roll_backward(cbor_array: Vec<Value>) -> i64 {
     let mut slot: i64 = 0;
-    match &cbor_array[1] {
-        Value::Array(block) => {
-            if block.len() > 0 {
+    match cbor_array.get(1) {
+        Some(Value::Array(block)) => {
+            if !block.is_empty() {
                 match block[0] {
                     Value::Integer(parsed_slot) => { slot = parsed_slot as i64 }
                     _ => { error!("invalid cbor"); }
@@ -490,3 +524,27 @@ pub fn parse_msg_roll_backward(cbor_array: Vec<Value>) -> i64 {
 
     slot
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receive(state: State, message: &str) -> Result<(), Error> {
+        let mut protocol = ChainSyncProtocol { state, ..ChainSyncProtocol::default() };
+        protocol.receive_data(hex::decode(message).unwrap())
+    }
+
+    #[test]
+    fn messages_are_checked_against_the_state() {
+        /* MsgAwaitReply */
+        assert!(receive(State::CanAwait, "8101").is_ok());
+        assert!(matches!(receive(State::MustReply, "8101"), Err(Error::ProtocolViolation(_))));
+        assert!(matches!(receive(State::Intersect, "8101"), Err(Error::ProtocolViolation(_))));
+        /* MsgIntersectNotFound with a tip at block 10 */
+        let not_found = "8206828218645820aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa0a";
+        assert!(receive(State::Intersect, not_found).is_ok());
+        assert!(matches!(receive(State::CanAwait, not_found), Err(Error::ProtocolViolation(_))));
+        /* MsgDone */
+        assert!(matches!(receive(State::CanAwait, "8107"), Err(Error::ProtocolViolation(_))));
+    }
+}
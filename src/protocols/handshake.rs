@@ -1,4 +1,4 @@
-/**
+/*
 Forked-off from https://github.com/AndrewWestberg/cncli/ on 2020-11-30
 © 2020 Andrew Westberg licensed under Apache-2.0
 
@@ -9,22 +9,389 @@ SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 
 */
 
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt,
+    time::Duration,
+};
 
 use log::debug;
 use serde_cbor::{de, ser, Value, Value::*};
 
-use crate::{Agency, Protocol};
+use crate::{Agency, Error, Protocol};
+
+pub const PROTOCOL_VERSION_1: u64 = 0x01;
+pub const PROTOCOL_VERSION_2: u64 = 0x02;
+pub const PROTOCOL_VERSION_SHELLEY: u64 = 0x03;
+pub const PROTOCOL_VERSION_SHELLEY2: u64 = 0x04;
+pub const PROTOCOL_VERSION_ALLEGRA: u64 = 0x05;
+pub const PROTOCOL_VERSION_MARY: u64 = 0x06;
+pub const PROTOCOL_VERSION_ALONZO: u64 = 0x07;
+pub const PROTOCOL_VERSION_ALONZO2: u64 = 0x08;
+pub const PROTOCOL_VERSION_BABBAGE: u64 = 0x09;
+pub const PROTOCOL_VERSION_BABBAGE2: u64 = 0x0a;
+pub const PROTOCOL_VERSION_BABBAGE3: u64 = 0x0b;
+pub const PROTOCOL_VERSION_BABBAGE4: u64 = 0x0c;
+pub const PROTOCOL_VERSION_CONWAY: u64 = 0x0d;
+pub const PROTOCOL_VERSION_CONWAY2: u64 = 0x0e;
+
+/* Versions offered by default, the oldest first. Current nodes only accept the newest ones. */
+const PROTOCOL_VERSIONS: [u64; 14] = [
+    PROTOCOL_VERSION_1,
+    PROTOCOL_VERSION_2,
+    PROTOCOL_VERSION_SHELLEY,
+    PROTOCOL_VERSION_SHELLEY2,
+    PROTOCOL_VERSION_ALLEGRA,
+    PROTOCOL_VERSION_MARY,
+    PROTOCOL_VERSION_ALONZO,
+    PROTOCOL_VERSION_ALONZO2,
+    PROTOCOL_VERSION_BABBAGE,
+    PROTOCOL_VERSION_BABBAGE2,
+    PROTOCOL_VERSION_BABBAGE3,
+    PROTOCOL_VERSION_BABBAGE4,
+    PROTOCOL_VERSION_CONWAY,
+    PROTOCOL_VERSION_CONWAY2,
+];
+
+/*
+ * Node-to-client versions have the high bit set, their parameters are just the network magic
+ * up to version 15, which adds the query field.
+ */
+pub const NODE_TO_CLIENT_VERSION_9: u64 = 0x8009;
+pub const NODE_TO_CLIENT_VERSION_10: u64 = 0x800a;
+pub const NODE_TO_CLIENT_VERSION_11: u64 = 0x800b;
+pub const NODE_TO_CLIENT_VERSION_12: u64 = 0x800c;
+pub const NODE_TO_CLIENT_VERSION_13: u64 = 0x800d;
+pub const NODE_TO_CLIENT_VERSION_14: u64 = 0x800e;
+pub const NODE_TO_CLIENT_VERSION_15: u64 = 0x800f;
+pub const NODE_TO_CLIENT_VERSION_16: u64 = 0x8010;
+
+const NODE_TO_CLIENT_VERSIONS: [u64; 8] = [
+    NODE_TO_CLIENT_VERSION_9,
+    NODE_TO_CLIENT_VERSION_10,
+    NODE_TO_CLIENT_VERSION_11,
+    NODE_TO_CLIENT_VERSION_12,
+    NODE_TO_CLIENT_VERSION_13,
+    NODE_TO_CLIENT_VERSION_14,
+    NODE_TO_CLIENT_VERSION_15,
+    NODE_TO_CLIENT_VERSION_16,
+];
+
+const NODE_TO_CLIENT_BIT: u64 = 0x8000;
+const NODE_TO_CLIENT_QUERY_VERSION: u64 = NODE_TO_CLIENT_VERSION_15;
+
+/*
+ * First versions whose parameters carry the diffusion mode, then the peer sharing and query
+ * fields. Before Conway peer sharing had a third value for public sharing.
+ */
+const DIFFUSION_MODE_VERSION: u64 = PROTOCOL_VERSION_SHELLEY2;
+const PEER_SHARING_VERSION: u64 = PROTOCOL_VERSION_BABBAGE3;
+const PEER_SHARING_FLAG_VERSION: u64 = PROTOCOL_VERSION_CONWAY;
+
+const MSG_PROPOSE_VERSIONS_MSG_ID: i128 = 0;
+const MSG_ACCEPT_VERSION_MSG_ID: i128 = 1;
+const MSG_REFUSE_MSG_ID: i128 = 2;
+const MSG_QUERY_REPLY_MSG_ID: i128 = 3;
+
+/* Reasons of MsgRefuse. */
+const REFUSE_VERSION_MISMATCH: i128 = 0;
+const REFUSE_HANDSHAKE_DECODE_ERROR: i128 = 1;
+const REFUSE_REFUSED: i128 = 2;
+
+/* Both handshake states time out after 10s according to the network spec. */
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/*
+ * Whether a node only initiates protocols on its outbound connections or also answers them, in
+ * which case both ends run every mini-protocol in both roles on a single connection.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DiffusionMode {
+    InitiatorOnly,
+    InitiatorAndResponder,
+}
 
-const PROTOCOL_VERSION_1: i128 = 0x01;
-const PROTOCOL_VERSION_2: i128 = 0x02;
-const PROTOCOL_VERSION_SHELLEY: i128 = 0x03;
-const PROTOCOL_VERSION_SHELLEY2: i128 = 0x04;
-const PROTOCOL_VERSION_ALLEGRA: i128 = 0x05;
-const PROTOCOL_VERSION_MARY: i128 = 0x06;
-const MIN_PROTOCOL_VERSION: i128 = PROTOCOL_VERSION_MARY;
+/*
+ * Parameters of a single version, each version only sends those it knows about. Node-to-client
+ * versions only ever send the network magic and the query flag.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionData {
+    pub network_magic: u32,
+    // Sent from version 4 on
+    pub diffusion_mode: DiffusionMode,
+    // Sent from version 11 on
+    pub peer_sharing: bool,
+    // Sent from version 11 on, asks the server for its versions instead of accepting one
+    pub query: bool,
+}
 
-const MSG_ACCEPT_VERSION_MSG_ID: i128 = 1;
+impl VersionData {
+    pub fn new(network_magic: u32) -> Self {
+        VersionData {
+            network_magic,
+            diffusion_mode: DiffusionMode::InitiatorOnly,
+            peer_sharing: false,
+            query: false,
+        }
+    }
+
+    /*
+     * What both sides agreed upon, the magic has been checked before. Duplex mode and peer
+     * sharing are only used if both of them asked for it.
+     */
+    fn combine(&self, peer: &VersionData) -> VersionData {
+        let diffusion_mode = match (self.diffusion_mode, peer.diffusion_mode) {
+            (DiffusionMode::InitiatorAndResponder, DiffusionMode::InitiatorAndResponder) => DiffusionMode::InitiatorAndResponder,
+            _ => DiffusionMode::InitiatorOnly,
+        };
+        VersionData {
+            network_magic: self.network_magic,
+            diffusion_mode,
+            peer_sharing: self.peer_sharing && peer.peer_sharing,
+            query: false,
+        }
+    }
+
+    fn to_value(&self, version: u64) -> Value {
+        let magic = Integer(self.network_magic.into());
+        if version & NODE_TO_CLIENT_BIT != 0 {
+            return match version < NODE_TO_CLIENT_QUERY_VERSION {
+                true => magic,
+                false => Array(vec![magic, Bool(self.query)]),
+            };
+        }
+        if version < DIFFUSION_MODE_VERSION {
+            return magic;
+        }
+        let mut items = vec![magic, Bool(self.diffusion_mode == DiffusionMode::InitiatorOnly)];
+        if version >= PEER_SHARING_VERSION {
+            items.push(Integer(self.peer_sharing as i128));
+            items.push(Bool(self.query));
+        }
+        Array(items)
+    }
+
+    fn from_value(version: u64, value: &Value) -> Option<Self> {
+        let magic = |value: &Value| match value {
+            Integer(magic) => u32::try_from(*magic).ok(),
+            _ => None,
+        };
+        if version & NODE_TO_CLIENT_BIT != 0 && version >= NODE_TO_CLIENT_QUERY_VERSION {
+            return match value {
+                Array(items) => match (items.first(), items.get(1)) {
+                    (Some(value), Some(Bool(query))) => Some(VersionData { query: *query, ..VersionData::new(magic(value)?) }),
+                    _ => None,
+                },
+                _ => None,
+            };
+        }
+        if version & NODE_TO_CLIENT_BIT != 0 || version < DIFFUSION_MODE_VERSION {
+            return Some(VersionData::new(magic(value)?));
+        }
+        let items = match value {
+            Array(items) => items,
+            _ => return None,
+        };
+        let diffusion_mode = match items.get(1)? {
+            Bool(true) => DiffusionMode::InitiatorOnly,
+            Bool(false) => DiffusionMode::InitiatorAndResponder,
+            _ => return None,
+        };
+        let (peer_sharing, query) = match (version >= PEER_SHARING_VERSION, items.get(2), items.get(3)) {
+            (false, _, _) => (false, false),
+            (true, Some(Integer(0)), Some(Bool(query))) => (false, *query),
+            (true, Some(Integer(1)), Some(Bool(query))) => (true, *query),
+            (true, Some(Integer(2)), Some(Bool(query))) if version < PEER_SHARING_FLAG_VERSION => (true, *query),
+            (true, _, _) => return None,
+        };
+        Some(VersionData {
+            network_magic: magic(items.first()?)?,
+            diffusion_mode,
+            peer_sharing,
+            query,
+        })
+    }
+}
+
+/*
+ * The versions a node offers as a client or accepts as a server along with their parameters,
+ * negotiation settles on the highest version in both tables. A network magic converts into
+ * the node-to-node table of all versions we speak.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VersionTable {
+    versions: BTreeMap<u64, VersionData>,
+}
+
+impl VersionTable {
+    pub fn new() -> Self {
+        VersionTable::default()
+    }
+
+    /* All node-to-node versions we speak. */
+    pub fn node_to_node(network_magic: u32) -> Self {
+        VersionTable::with_all(&PROTOCOL_VERSIONS, network_magic)
+    }
+
+    /* All node-to-client versions we speak, for the local socket of a node. */
+    pub fn node_to_client(network_magic: u32) -> Self {
+        VersionTable::with_all(&NODE_TO_CLIENT_VERSIONS, network_magic)
+    }
+
+    fn with_all(versions: &[u64], network_magic: u32) -> Self {
+        versions.iter()
+            .fold(VersionTable::new(), |table, version| table.with(*version, VersionData::new(network_magic)))
+    }
+
+    /* Offer a version, replacing the parameters it might have had. */
+    pub fn with(mut self, version: u64, data: VersionData) -> Self {
+        self.versions.insert(version, data);
+        self
+    }
+
+    /* Stop offering versions outside of the range. */
+    pub fn restrict(mut self, versions: impl std::ops::RangeBounds<u64>) -> Self {
+        self.versions.retain(|version, _| versions.contains(version));
+        self
+    }
+
+    /* Use the diffusion mode for every version. */
+    pub fn with_diffusion_mode(mut self, diffusion_mode: DiffusionMode) -> Self {
+        for data in self.versions.values_mut() {
+            data.diffusion_mode = diffusion_mode;
+        }
+        self
+    }
+
+    pub fn get(&self, version: u64) -> Option<&VersionData> {
+        self.versions.get(&version)
+    }
+
+    pub fn versions(&self) -> impl Iterator<Item = u64> + '_ {
+        self.versions.keys().copied()
+    }
+
+    /*
+     * Ask the server for its versions, only node-to-node versions from 11 on and node-to-client
+     * versions from 15 on can do so.
+     */
+    pub fn with_query(mut self) -> Self {
+        for data in self.versions.values_mut() {
+            data.query = true;
+        }
+        self
+    }
+
+    /* Whether the table asks for the versions of the server rather than for one of them. */
+    pub fn is_query(&self) -> bool {
+        self.versions.values().any(|data| data.query)
+    }
+
+    fn to_value(&self) -> Value {
+        Map(self.versions.iter()
+            .map(|(version, data)| (Integer(*version as i128), data.to_value(*version)))
+            .collect())
+    }
+
+    /* Versions with parameters we do not understand are left out. */
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Map(versions) => Some(VersionTable {
+                versions: versions.iter()
+                    .filter_map(|(version, data)| match version {
+                        Integer(version) => {
+                            let version = u64::try_from(*version).ok()?;
+                            Some((version, VersionData::from_value(version, data)?))
+                        }
+                        _ => None,
+                    })
+                    .collect(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl From<u32> for VersionTable {
+    fn from(network_magic: u32) -> Self {
+        VersionTable::node_to_node(network_magic)
+    }
+}
+
+/* Outcome of a successful handshake. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub version: u64,
+    // Parameters both sides agreed upon
+    pub data: VersionData,
+}
+
+/* Why a handshake was refused, as carried by MsgRefuse. */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Refusal {
+    // No common version, along with the versions of the refusing side
+    VersionMismatch(Vec<u64>),
+    // The parameters of the version could not be decoded
+    HandshakeDecodeError(u64, String),
+    // The parameters of the version were not acceptable
+    Refused(u64, String),
+}
+
+impl Refusal {
+    fn to_value(&self) -> Value {
+        Array(match self {
+            Refusal::VersionMismatch(versions) => vec![
+                Integer(REFUSE_VERSION_MISMATCH),
+                Array(versions.iter().map(|version| Integer(*version as i128)).collect()),
+            ],
+            Refusal::HandshakeDecodeError(version, message) => vec![
+                Integer(REFUSE_HANDSHAKE_DECODE_ERROR),
+                Integer(*version as i128),
+                Text(message.clone()),
+            ],
+            Refusal::Refused(version, message) => vec![
+                Integer(REFUSE_REFUSED),
+                Integer(*version as i128),
+                Text(message.clone()),
+            ],
+        })
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        let items = match value {
+            Array(items) => items,
+            _ => return None,
+        };
+        let version = |value: &Value| match value {
+            Integer(version) => u64::try_from(*version).ok(),
+            _ => None,
+        };
+        match (items.first()?, items.get(1)?, items.get(2)) {
+            (Integer(REFUSE_VERSION_MISMATCH), Array(versions), None) => {
+                Some(Refusal::VersionMismatch(versions.iter().map(version).collect::<Option<_>>()?))
+            }
+            (Integer(REFUSE_HANDSHAKE_DECODE_ERROR), number, Some(Text(message))) => {
+                Some(Refusal::HandshakeDecodeError(version(number)?, message.clone()))
+            }
+            (Integer(REFUSE_REFUSED), number, Some(Text(message))) => {
+                Some(Refusal::Refused(version(number)?, message.clone()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::VersionMismatch(versions) => write!(f, "version mismatch, supported versions {:?}", versions),
+            Refusal::HandshakeDecodeError(version, message) => write!(f, "version {} could not be decoded: {}", version, message),
+            Refusal::Refused(version, message) => write!(f, "version {} refused: {}", version, message),
+        }
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub enum State {
@@ -35,139 +402,154 @@ pub enum State {
 
 pub struct HandshakeProtocol {
     role: Agency,
-    network_magic: u32,
+    versions: VersionTable,
     state: State,
-    result: Option<Result<String, String>>,
+    result: Option<Result<String, Error>>,
+    negotiated: Option<Handshake>,
+    queried: Option<VersionTable>,
+    /* The responder's answer to the proposal, MsgAcceptVersion or MsgRefuse. */
+    reply: Option<Value>,
 }
 
 impl HandshakeProtocol {
-    pub fn new(network_magic: u32) -> Self {
+    pub fn new(versions: impl Into<VersionTable>) -> Self {
         HandshakeProtocol {
             role: Agency::Client,
-            network_magic,
+            versions: versions.into(),
             state: State::Propose,
             result: None,
+            negotiated: None,
+            queried: None,
+            reply: None,
         }
     }
 
-    pub fn expect(network_magic: u32) -> Self {
+    pub fn expect(versions: impl Into<VersionTable>) -> Self {
         HandshakeProtocol {
             role: Agency::Server,
-            network_magic,
-            state: State::Propose,
-            result: None,
+            ..HandshakeProtocol::new(versions)
         }
     }
 
+    /* Offer or accept running the protocols in both directions, the default is InitiatorOnly. */
+    pub fn with_diffusion_mode(mut self, diffusion_mode: DiffusionMode) -> Self {
+        self.versions = self.versions.with_diffusion_mode(diffusion_mode);
+        self
+    }
+
+    /* The mode both sides agreed upon, duplex only if both of them asked for it. */
+    pub fn diffusion_mode(&self) -> Option<DiffusionMode> {
+        self.negotiated.as_ref().map(|handshake| handshake.data.diffusion_mode)
+    }
+
+    /* The version and parameters agreed upon, once the handshake succeeded. */
+    pub fn negotiated(&self) -> Option<&Handshake> {
+        self.negotiated.as_ref()
+    }
+
+    /* The versions of the server, if we asked for them with a query. */
+    pub fn queried(&self) -> Option<&VersionTable> {
+        self.queried.as_ref()
+    }
+
     // Serialize cbor for MsgProposeVersions
     //
     // Create the byte representation of MsgProposeVersions for sending to the server
-    fn msg_propose_versions(&self, network_magic: u32) -> Vec<u8> {
-        let mut payload_map: BTreeMap<Value, Value> = BTreeMap::new();
-        payload_map.insert(Value::Integer(PROTOCOL_VERSION_1), Value::Integer(network_magic as i128));
-        payload_map.insert(Value::Integer(PROTOCOL_VERSION_2), Value::Integer(network_magic as i128));
-        payload_map.insert(Value::Integer(PROTOCOL_VERSION_SHELLEY), Value::Integer(network_magic as i128));
-        payload_map.insert(Value::Integer(PROTOCOL_VERSION_SHELLEY2), Value::Array(vec![Value::Integer(network_magic as i128), Value::Bool(false)]));
-        payload_map.insert(Value::Integer(PROTOCOL_VERSION_ALLEGRA), Value::Array(vec![Value::Integer(network_magic as i128), Value::Bool(false)]));
-        payload_map.insert(Value::Integer(PROTOCOL_VERSION_MARY), Value::Array(vec![Value::Integer(network_magic as i128), Value::Bool(false)]));
-
+    fn msg_propose_versions(&self) -> Vec<u8> {
         let message = Value::Array(vec![
-            Value::Integer(0), // message_id
-            Value::Map(payload_map)
+            Value::Integer(MSG_PROPOSE_VERSIONS_MSG_ID), // message_id
+            self.versions.to_value(),
         ]);
 
         ser::to_vec_packed(&message).unwrap()
     }
 
-    // Search through the cbor values until we find a Text value.
-    fn find_error_message(&self, cbor_value: &Value) -> Result<String, ()> {
-        match cbor_value {
-            Value::Text(cbor_text) => {
-                return Ok(cbor_text.to_owned());
-            }
-            Value::Array(cbor_array) => {
-                for value in cbor_array {
-                    let result = self.find_error_message(value);
-                    if result.is_ok() {
-                        return result;
-                    }
+    /*
+     * Pick the highest version both sides speak and check its parameters. Returns the reply to
+     * send along with the outcome of the handshake on our side.
+     */
+    fn accept_version(&mut self, propose: Value) -> Result<(Value, Result<String, Error>), Error> {
+        let proposed = match propose {
+            Array(items) if items.first() == Some(&Integer(MSG_PROPOSE_VERSIONS_MSG_ID)) => match items.into_iter().nth(1) {
+                Some(Map(versions)) => versions,
+                _ => return Err(Error::Decode("MsgProposeVersions without versions".to_string())),
+            },
+            message => return Err(Error::ProtocolViolation(format!("expected MsgProposeVersions, got {:?}", message))),
+        };
+
+        let common = self.versions.versions.iter().rev()
+            .find_map(|(version, ours)| Some((*version, ours, proposed.get(&Integer(*version as i128))?)));
+        let refusal = match common {
+            None => Refusal::VersionMismatch(self.versions.versions().collect()),
+            Some((version, ours, data)) => match VersionData::from_value(version, data) {
+                None => Refusal::HandshakeDecodeError(version, format!("Unable to parse version data {:?}", data)),
+                Some(theirs) if theirs.network_magic != ours.network_magic => {
+                    Refusal::Refused(version, format!("Expected network magic {}, but was {}", ours.network_magic, theirs.network_magic))
                 }
-            }
-            _ => {}
-        }
-        return Err(());
+                Some(theirs) if theirs.query => {
+                    debug!("answering version query");
+                    let reply = Array(vec![Integer(MSG_QUERY_REPLY_MSG_ID), self.versions.to_value()]);
+                    return Ok((reply, Ok("queried".to_string())));
+                }
+                Some(theirs) => {
+                    let agreed = ours.combine(&theirs);
+                    let accept = Array(vec![Integer(MSG_ACCEPT_VERSION_MSG_ID), Integer(version as i128), agreed.to_value(version)]);
+                    debug!("accepting version {}", version);
+                    self.negotiated = Some(Handshake { version, data: agreed });
+                    return Ok((accept, Ok("confirmed".to_string())));
+                }
+            },
+        };
+        debug!("refusing: {}", refusal);
+        Ok((
+            Array(vec![Integer(MSG_REFUSE_MSG_ID), refusal.to_value()]),
+            Err(Error::HandshakeRefused(refusal)),
+        ))
     }
 
-    fn validate_data(&self, confirm: Value, hex_data: String) -> Result<String, String> {
+    fn validate_data(&mut self, confirm: Value, hex_data: String) -> Result<String, Error> {
+        let decode_error = || Error::Decode(format!("Unable to parse payload error! {}", hex_data));
         let confirm_vec = match &confirm {
-            Value::Array(confirm_vec) => { Ok(confirm_vec) }
-            _ => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-        }?;
-
-        let msg_type = match confirm_vec.get(0) {
-            Some(msg_type) => { Ok(msg_type) }
-            None => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-        }?;
-
-        let _msg_type_int = match msg_type {
-            Value::Integer(msg_type_int) => {
-                if *msg_type_int == MSG_ACCEPT_VERSION_MSG_ID {
-                    Ok(msg_type_int)
-                } else {
-                    match self.find_error_message(&confirm) {
-                        Ok(error_message) => {
-                            Err(error_message)
-                        }
-                        Err(_) => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-                    }
-                }
-            }
-            _ => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-        }?;
-
-        let accepted_protocol_value = match confirm_vec.get(1) {
-            Some(accepted_protocol_value) => { Ok(accepted_protocol_value) }
-            None => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-        }?;
-
-        let _accepted_protocol = match accepted_protocol_value {
-            Value::Integer(accepted_protocol) => {
-                if *accepted_protocol < MIN_PROTOCOL_VERSION {
-                    Err(format!("Expected protocol version {}, but was {}", MIN_PROTOCOL_VERSION, accepted_protocol))
-                } else {
-                    Ok(accepted_protocol)
-                }
+            Value::Array(confirm_vec) => confirm_vec,
+            _ => return Err(decode_error()),
+        };
+
+        match confirm_vec.first() {
+            Some(Integer(MSG_ACCEPT_VERSION_MSG_ID)) => {}
+            Some(Integer(MSG_REFUSE_MSG_ID)) => {
+                let refusal = confirm_vec.get(1).and_then(Refusal::from_value).ok_or_else(decode_error)?;
+                return Err(Error::HandshakeRefused(refusal));
             }
-            _ => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-        }?;
-
-        let accepted_vec_value = match confirm_vec.get(2) {
-            Some(accepted_vec_value) => { Ok(accepted_vec_value) }
-            None => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-        }?;
-
-        let accepted_vec = match accepted_vec_value {
-            Value::Array(accepted_vec) => { Ok(accepted_vec) }
-            _ => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-        }?;
-
-        let accepted_magic_value = match accepted_vec.get(0) {
-            Some(accepted_magic_value) => { Ok(accepted_magic_value) }
-            None => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-        }?;
-
-        let _accepted_magic = match accepted_magic_value {
-            Value::Integer(accepted_magic) => {
-                if *accepted_magic == self.network_magic as i128 {
-                    Ok(accepted_magic)
-                } else {
-                    Err(format!("Expected network magic {}, but was {}", self.network_magic, accepted_magic))
-                }
+            Some(Integer(MSG_QUERY_REPLY_MSG_ID)) => {
+                self.queried = Some(confirm_vec.get(1).and_then(VersionTable::from_value).ok_or_else(decode_error)?);
+                return Ok(hex_data);
             }
-            _ => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-        }?;
+            _ => return Err(decode_error()),
+        }
+
+        let accepted_protocol = match confirm_vec.get(1) {
+            Some(Integer(accepted_protocol)) => u64::try_from(*accepted_protocol).map_err(|_| decode_error())?,
+            _ => return Err(decode_error()),
+        };
+        let ours = self.versions.get(accepted_protocol).ok_or_else(|| {
+            Error::ProtocolViolation(format!("Peer accepted version {}, which we did not propose", accepted_protocol))
+        })?;
+
+        let theirs = confirm_vec.get(2)
+            .and_then(|data| VersionData::from_value(accepted_protocol, data))
+            .ok_or_else(decode_error)?;
+        if theirs.network_magic != ours.network_magic {
+            return Err(Error::HandshakeRefused(Refusal::Refused(
+                accepted_protocol,
+                format!("Expected network magic {}, but was {}", ours.network_magic, theirs.network_magic),
+            )));
+        }
 
-        return Ok(hex_data);
+        self.negotiated = Some(Handshake {
+            version: accepted_protocol,
+            data: ours.combine(&theirs),
+        });
+        Ok(hex_data)
     }
 }
 
@@ -181,8 +563,8 @@ impl Protocol for HandshakeProtocol {
         }
     }
 
-    fn result(&self) -> Result<String, String> {
-        self.result.clone().unwrap_or(Err("no result".to_string()))
+    fn result(&self) -> Result<String, Error> {
+        self.result.clone().unwrap_or_else(|| Err(Error::ProtocolViolation("no result".to_string())))
     }
 
     fn role(&self) -> Agency {
@@ -190,58 +572,62 @@ impl Protocol for HandshakeProtocol {
     }
 
     fn agency(&self) -> Agency {
-        return match self.state {
+        match self.state {
             State::Propose => { Agency::Client }
             State::Confirm => { Agency::Server }
             State::Done => { Agency::None }
-        };
+        }
     }
 
     fn state(&self) -> String {
         format!("{:?}", self.state)
     }
 
+    fn timeout(&self) -> Option<Duration> {
+        match self.state {
+            State::Propose | State::Confirm => Some(HANDSHAKE_TIMEOUT),
+            State::Done => None,
+        }
+    }
+
     fn send_data(&mut self) -> Option<Vec<u8>> {
         debug!("send: {:?}", self.state);
         match self.state {
             State::Propose => {
-                let payload = self.msg_propose_versions(self.network_magic);
+                let payload = self.msg_propose_versions();
                 self.state = State::Confirm;
                 Some(payload)
             }
             State::Confirm => {
-                /* TODO: [stub] implement proper negotiation, we now use fixed protocol version */
-                self.result = Some(Ok("confirmed".to_string()));
                 self.state = State::Done;
-                Some(ser::to_vec(
-                    &Array(vec![
-                           Integer(1),
-                           Integer(6),
-                           Array(vec![
-                               Integer(self.network_magic.into()),
-                               Bool(false),
-                           ]),
-                    ])
-                ).unwrap())
+                self.reply.take().map(|reply| ser::to_vec(&reply).unwrap())
             }
-            State::Done => panic!("unexpected send"),
+            State::Done => None,
         }
     }
 
-    fn receive_data(&mut self, data: Vec<u8>) {
+    fn receive_data(&mut self, data: Vec<u8>) -> Result<(), Error> {
         debug!("recv: {:?}", self.state);
         match self.state {
             State::Propose => {
+                let propose: Value = de::from_slice(&data[..])?;
+                debug!("Propose: {:?}", &propose);
+                let (reply, result) = self.accept_version(propose)?;
+                self.reply = Some(reply);
+                self.result = Some(result);
                 self.state = State::Confirm;
             }
             State::Confirm => {
-                let confirm: Value = de::from_slice(&data[..]).unwrap();
+                let confirm: Value = de::from_slice(&data[..])?;
                 debug!("Confirm: {:?}", &confirm);
                 self.result = Some(self.validate_data(confirm, hex::encode(data)));
                 self.state = State::Done;
             }
-            State::Done => panic!("unexpected recv"),
+            State::Done => {
+                return Err(Error::ProtocolViolation("unexpected handshake message".to_string()));
+            }
         }
+        Ok(())
     }
 }
 
@@ -250,40 +636,29 @@ mod tests {
     use super::*;
     use std::assert_eq;
 
-    fn propose(magic: u32) -> Vec<u8> {
+    fn data(version: i128, magic: u32, initiator_only: bool) -> Value {
+        match version {
+            1..=3 => magic.into(),
+            4..=10 => Array(vec![Integer(magic.into()), Bool(initiator_only)]),
+            _ => Array(vec![Integer(magic.into()), Bool(initiator_only), Integer(0), Bool(false)]),
+        }
+    }
+
+    fn propose(magic: u32, initiator_only: bool) -> Vec<u8> {
         ser::to_vec(
             &Array(vec![
                 Integer(0),
-                Map(vec![
-                    (Integer(1), magic.into()),
-                    (Integer(2), magic.into()),
-                    (Integer(3), magic.into()),
-                    (Integer(4), Array(vec![
-                        Integer(magic.into()),
-                        Bool(false),
-                    ])),
-                    (Integer(5), Array(vec![
-                        Integer(magic.into()),
-                        Bool(false),
-                    ])),
-                    (Integer(6), Array(vec![
-                        Integer(magic.into()),
-                        Bool(false),
-                    ])),
-                ].into_iter().collect::<BTreeMap<Value,Value>>()),
+                Map((1..=14).map(|version| (Integer(version), data(version, magic, initiator_only))).collect()),
             ])
         ).unwrap()
     }
 
-    fn confirm(magic: u32) -> Vec<u8> {
+    fn confirm(magic: u32, initiator_only: bool) -> Vec<u8> {
         ser::to_vec(
             &Array(vec![
                    Integer(1),
-                   Integer(6),
-                   Array(vec![
-                       Integer(magic.into()),
-                       Bool(false),
-                   ]),
+                   Integer(14),
+                   data(14, magic, initiator_only),
             ]),
         ).unwrap()
     }
@@ -295,9 +670,10 @@ mod tests {
         assert_eq!(client.state, State::Propose);
         let data = client.send_data().unwrap();
         assert_eq!(client.state, State::Confirm);
-        assert_eq!(data, propose(magic));
-        client.receive_data(confirm(magic));
+        assert_eq!(data, propose(magic, true));
+        client.receive_data(confirm(magic, true)).unwrap();
         assert_eq!(client.state, State::Done);
+        assert_eq!(client.diffusion_mode(), Some(DiffusionMode::InitiatorOnly));
     }
 
     #[test]
@@ -305,10 +681,160 @@ mod tests {
         let magic = 0xdddddddd;
         let mut server = HandshakeProtocol::expect(magic);
         assert_eq!(server.state, State::Propose);
-        server.receive_data(propose(magic));
+        server.receive_data(propose(magic, true)).unwrap();
         assert_eq!(server.state, State::Confirm);
         let data = server.send_data().unwrap();
         assert_eq!(server.state, State::Done);
-        assert_eq!(data, confirm(magic));
+        assert_eq!(data, confirm(magic, true));
+    }
+
+    #[test]
+    fn duplex_needs_both_sides() {
+        let magic = 0xdddddddd;
+        let mut client = HandshakeProtocol::new(magic).with_diffusion_mode(DiffusionMode::InitiatorAndResponder);
+        assert_eq!(client.send_data().unwrap(), propose(magic, false));
+
+        let mut server = HandshakeProtocol::expect(magic);
+        server.receive_data(propose(magic, false)).unwrap();
+        assert_eq!(server.send_data().unwrap(), confirm(magic, true));
+        assert_eq!(server.diffusion_mode(), Some(DiffusionMode::InitiatorOnly));
+
+        let mut server = HandshakeProtocol::expect(magic).with_diffusion_mode(DiffusionMode::InitiatorAndResponder);
+        server.receive_data(propose(magic, false)).unwrap();
+        assert_eq!(server.send_data().unwrap(), confirm(magic, false));
+        assert_eq!(server.diffusion_mode(), Some(DiffusionMode::InitiatorAndResponder));
+
+        client.receive_data(confirm(magic, false)).unwrap();
+        assert_eq!(client.diffusion_mode(), Some(DiffusionMode::InitiatorAndResponder));
+    }
+
+    #[test]
+    fn highest_common_version_is_chosen() {
+        let magic = 0xdddddddd;
+        let mut server = HandshakeProtocol::expect(magic);
+        server.receive_data(ser::to_vec(&Array(vec![
+            Integer(0),
+            Map(vec![
+                (Integer(3), magic.into()),
+                (Integer(5), Array(vec![Integer(magic.into()), Bool(true)])),
+                (Integer(99), Array(vec![Integer(magic.into()), Bool(true)])),
+            ].into_iter().collect()),
+        ])).unwrap()).unwrap();
+        let accept: Value = de::from_slice(&server.send_data().unwrap()).unwrap();
+        assert_eq!(accept, Array(vec![Integer(1), Integer(5), Array(vec![Integer(magic.into()), Bool(true)])]));
+        assert!(server.result().is_ok());
+    }
+
+    #[test]
+    fn mismatches_are_refused() {
+        let magic = 0xdddddddd;
+        let mut server = HandshakeProtocol::expect(magic);
+        server.receive_data(ser::to_vec(&Array(vec![
+            Integer(0),
+            Map(vec![(Integer(99), magic.into())].into_iter().collect()),
+        ])).unwrap()).unwrap();
+        let refuse = server.send_data().unwrap();
+        assert_eq!(server.state, State::Done);
+        assert!(matches!(server.result(), Err(Error::HandshakeRefused(Refusal::VersionMismatch(_)))));
+
+        let mut client = HandshakeProtocol::new(magic);
+        client.send_data().unwrap();
+        client.receive_data(refuse).unwrap();
+        match client.result() {
+            Err(Error::HandshakeRefused(Refusal::VersionMismatch(versions))) => assert_eq!(versions, (1..=14).collect::<Vec<_>>()),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        let mut server = HandshakeProtocol::expect(magic);
+        server.receive_data(propose(1, true)).unwrap();
+        let refuse = server.send_data().unwrap();
+        let mut client = HandshakeProtocol::new(magic);
+        client.send_data().unwrap();
+        client.receive_data(refuse).unwrap();
+        let refusal = Refusal::Refused(14, format!("Expected network magic {}, but was 1", magic));
+        assert!(matches!(server.result(), Err(Error::HandshakeRefused(ref server_refusal)) if *server_refusal == refusal));
+        assert!(matches!(client.result(), Err(Error::HandshakeRefused(ref client_refusal)) if *client_refusal == refusal));
+
+        let mut server = HandshakeProtocol::expect(magic);
+        server.receive_data(ser::to_vec(&Array(vec![
+            Integer(0),
+            Map(vec![(Integer(6), magic.into())].into_iter().collect()),
+        ])).unwrap()).unwrap();
+        server.send_data().unwrap();
+        assert!(matches!(server.result(), Err(Error::HandshakeRefused(Refusal::HandshakeDecodeError(6, _)))));
+    }
+
+    #[test]
+    fn versions_can_be_pinned() {
+        let magic = 0xdddddddd;
+        let versions = VersionTable::from(magic).restrict(PROTOCOL_VERSION_SHELLEY2..=PROTOCOL_VERSION_ALLEGRA);
+        assert_eq!(versions.versions().collect::<Vec<_>>(), vec![4, 5]);
+
+        let mut client = HandshakeProtocol::new(magic);
+        let mut server = HandshakeProtocol::expect(versions);
+        server.receive_data(client.send_data().unwrap()).unwrap();
+        client.receive_data(server.send_data().unwrap()).unwrap();
+        assert_eq!(client.negotiated(), Some(&Handshake {
+            version: PROTOCOL_VERSION_ALLEGRA,
+            data: VersionData::new(magic),
+        }));
+        assert_eq!(server.negotiated(), client.negotiated());
+    }
+
+    #[test]
+    fn peer_sharing_is_sent_from_version_11() {
+        let data = VersionData { peer_sharing: true, ..VersionData::new(42) };
+        assert_eq!(data.to_value(10), Array(vec![Integer(42), Bool(true)]));
+        assert_eq!(data.to_value(11), Array(vec![Integer(42), Bool(true), Integer(1), Bool(false)]));
+        assert_eq!(VersionData::from_value(11, &data.to_value(11)), Some(data));
+        /* Public peer sharing is gone with Conway. */
+        let public = Array(vec![Integer(42), Bool(true), Integer(2), Bool(false)]);
+        assert!(VersionData::from_value(12, &public).unwrap().peer_sharing);
+        assert_eq!(VersionData::from_value(13, &public), None);
+    }
+
+    #[test]
+    fn versions_can_be_queried() {
+        let magic = 0xdddddddd;
+        let mut client = HandshakeProtocol::new(VersionTable::from(magic).restrict(PROTOCOL_VERSION_BABBAGE3..).with_query());
+        let mut server = HandshakeProtocol::expect(VersionTable::from(magic).restrict(..=PROTOCOL_VERSION_CONWAY));
+        server.receive_data(client.send_data().unwrap()).unwrap();
+        client.receive_data(server.send_data().unwrap()).unwrap();
+        assert_eq!(server.negotiated(), None);
+        assert_eq!(client.negotiated(), None);
+        assert_eq!(client.queried().unwrap().versions().collect::<Vec<_>>(), (1..=13).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn node_to_client_versions_are_negotiated() {
+        let magic = 0xdddddddd;
+        let mut client = HandshakeProtocol::new(VersionTable::node_to_client(magic));
+        let propose: Value = de::from_slice(&client.send_data().unwrap()).unwrap();
+        let versions = match propose {
+            Array(ref items) => match &items[1] {
+                Map(versions) => versions.clone(),
+                _ => panic!("no versions in {:?}", propose),
+            },
+            _ => panic!("unexpected proposal {:?}", propose),
+        };
+        assert_eq!(versions[&Integer(0x800e)], Integer(magic.into()));
+        assert_eq!(versions[&Integer(0x8010)], Array(vec![Integer(magic.into()), Bool(false)]));
+
+        let mut server = HandshakeProtocol::expect(VersionTable::node_to_client(magic).restrict(..=NODE_TO_CLIENT_VERSION_14));
+        server.receive_data(ser::to_vec(&propose).unwrap()).unwrap();
+        client.receive_data(server.send_data().unwrap()).unwrap();
+        assert_eq!(client.negotiated().map(|handshake| handshake.version), Some(NODE_TO_CLIENT_VERSION_14));
+
+        let mut server = HandshakeProtocol::expect(magic);
+        server.receive_data(ser::to_vec(&propose).unwrap()).unwrap();
+        server.send_data().unwrap();
+        assert!(matches!(server.result(), Err(Error::HandshakeRefused(Refusal::VersionMismatch(_)))));
+    }
+
+    #[test]
+    fn handshake_garbage_is_rejected() {
+        let mut client = HandshakeProtocol::new(0xdddddddd);
+        client.send_data().unwrap();
+        assert!(matches!(client.receive_data(vec![0x83, 0x01]), Err(Error::Decode(_))));
     }
 }
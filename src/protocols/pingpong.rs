@@ -1,4 +1,4 @@
-/**
+/*
 © 2020 PERLUR Group
 
 SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
@@ -7,9 +7,11 @@ SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 
 use crate::{
     Agency,
+    Error,
     Protocol,
 };
-use log::{trace, error};
+use log::trace;
+use serde_cbor::{de, ser, Value};
 
 pub struct PingPongProtocol {
     role: Agency,
@@ -28,19 +30,41 @@ enum State {
 enum MessageType {
     Ping,
     Pong,
-    //Done,
+    Done,
+}
+
+impl MessageType {
+    fn to_bytes(&self) -> Vec<u8> {
+        let id = match self {
+            MessageType::Ping => 0,
+            MessageType::Pong => 1,
+            MessageType::Done => 2,
+        };
+        ser::to_vec(&Value::Array(vec![Value::Integer(id)])).unwrap()
+    }
+
+    fn from_bytes(payload: &[u8]) -> Result<MessageType, Error> {
+        match de::from_slice(payload)? {
+            Value::Array(items) => match items.first() {
+                Some(Value::Integer(0)) => Ok(MessageType::Ping),
+                Some(Value::Integer(1)) => Ok(MessageType::Pong),
+                Some(Value::Integer(2)) => Ok(MessageType::Done),
+                _ => Err(Error::Decode(format!("unknown ping-pong message {:?}", items))),
+            },
+            value => Err(Error::Decode(format!("unknown ping-pong message {:?}", value))),
+        }
+    }
 }
 
-fn transition(state: State, agency: Agency, message: MessageType) -> State {
+fn transition(state: State, agency: Agency, message: MessageType) -> Result<State, Error> {
     trace!("Transition from {:?} by {:?} message {:?}.", state, agency, message);
     match (state, agency, message) {
-        (State::Idle, Agency::Client, MessageType::Ping) => State::Busy,
-        //(State::Idle, Agency::Client, MessageType::Done) => State::Done,
-        (State::Busy, Agency::Server, MessageType::Pong) => State::Idle,
-        _ => {
-            error!("protocol violation");
-            State::Done
-        }
+        (State::Idle, Agency::Client, MessageType::Ping) => Ok(State::Busy),
+        (State::Idle, Agency::Client, MessageType::Done) => Ok(State::Done),
+        (State::Busy, Agency::Server, MessageType::Pong) => Ok(State::Idle),
+        (state, agency, message) => Err(Error::ProtocolViolation(format!(
+            "unexpected {:?} from {:?} in state {:?}", message, agency, state,
+        ))),
     }
 }
 
@@ -87,39 +111,45 @@ impl Protocol for PingPongProtocol {
         }
     }
 
-    fn result(&self) -> Result<String, String> {
+    fn result(&self) -> Result<String, Error> {
         Ok("no result".to_string())
     }
 
-    fn receive_data(&mut self, _payload: Vec<u8>) {
-        /* TODO: parse the message */
+    fn terminate(&mut self) -> Option<Vec<u8>> {
         match self.state {
             State::Idle => {
-                self.state = transition(self.state, self.agency(), MessageType::Ping);
-                trace!("Ping received!");
-            }
-            State::Busy => {
-                self.state = transition(self.state, self.agency(), MessageType::Pong);
-                trace!("Pong received!");
+                trace!("Sending done!");
+                let message = MessageType::Done;
+                let payload = message.to_bytes();
+                self.state = transition(self.state, self.agency(), message).ok()?;
+                Some(payload)
             }
-            State::Done => panic!("unexpected recv"),
+            _ => None,
         }
     }
 
+    fn receive_data(&mut self, payload: Vec<u8>) -> Result<(), Error> {
+        let message = MessageType::from_bytes(&payload)?;
+        trace!("{:?} received!", message);
+        self.state = transition(self.state, self.agency(), message)?;
+        Ok(())
+    }
+
     fn send_data(&mut self) -> Option<Vec<u8>> {
-        /* TODO: assemble the message */
-        match self.state {
+        let message = match self.state {
             State::Idle => {
-                self.state = transition(self.state, self.agency(), MessageType::Ping);
                 trace!("Sending ping!");
+                MessageType::Ping
             }
             State::Busy => {
-                self.state = transition(self.state, self.agency(), MessageType::Pong);
                 trace!("Sending pong!");
+                MessageType::Pong
             }
-            State::Done => panic!("unexpected send"),
-        }
+            State::Done => return None,
+        };
+        let payload = message.to_bytes();
+        self.state = transition(self.state, self.agency(), message).ok()?;
 
-        Some(vec![])
+        Some(payload)
     }
 }
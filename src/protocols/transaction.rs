@@ -1,4 +1,4 @@
-/**
+/*
 Forked-off from https://github.com/AndrewWestberg/cncli/ on 2020-11-30
 © 2020 Andrew Westberg licensed under Apache-2.0
 
@@ -10,10 +10,10 @@ SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 */
 
 use byteorder::WriteBytesExt;
-use log::{debug, error, warn};
+use log::{debug, warn};
 use serde_cbor::{de, Value};
 
-use crate::{Agency, Protocol};
+use crate::{Agency, Error, Protocol};
 
 #[derive(Debug)]
 pub enum State {
@@ -26,7 +26,7 @@ pub enum State {
 
 pub struct TxSubmissionProtocol {
     pub(crate) state: State,
-    pub(crate) result: Option<Result<String, String>>,
+    pub(crate) result: Option<Result<String, Error>>,
 }
 
 impl Default for TxSubmissionProtocol {
@@ -36,6 +36,9 @@ impl Default for TxSubmissionProtocol {
 }
 
 impl TxSubmissionProtocol {
+    // Ingress queue limit of the reference implementation
+    const INGRESS_LIMIT: usize = 721424;
+
     fn msg_reply_tx_ids(&self) -> Vec<u8> {
         // We need to do manual cbor encoding to do the empty indefinite array for txs.
         // We always just tell the server we have no transactions to send it.
@@ -44,17 +47,21 @@ impl TxSubmissionProtocol {
         message.write_u8(0x01).unwrap(); // message id for ReplyTxIds is 1
         message.write_u8(0x9f).unwrap(); // indefinite array start
         message.write_u8(0xff).unwrap(); // indefinite array end
-        return message;
+        message
+    }
+
+    fn msg_done(&self) -> Vec<u8> {
+        vec![0x81, 0x04] // array of length 1 with the message id for Done
     }
 }
 
 impl Protocol for TxSubmissionProtocol {
     fn protocol_id(&self) -> u16 {
-        return 0x0004u16;
+        0x0004u16
     }
 
-    fn result(&self) -> Result<String, String> {
-        self.result.clone().unwrap()
+    fn result(&self) -> Result<String, Error> {
+        self.result.clone().unwrap_or_else(|| Err(Error::ProtocolViolation("no result".to_string())))
     }
 
     fn role(&self) -> Agency {
@@ -62,22 +69,26 @@ impl Protocol for TxSubmissionProtocol {
     }
 
     fn agency(&self) -> Agency {
-        return match self.state {
+        match self.state {
             State::Idle => { Agency::Server }
             State::TxIdsBlocking => { Agency::Client }
             State::TxIdsNonBlocking => { Agency::Client }
             State::Done => {
                 if self.result.is_none() { Agency::Client } else { Agency::None }
             }
-        };
+        }
     }
 
     fn state(&self) -> String {
         format!("{:?}", self.state)
     }
 
+    fn ingress_limit(&self) -> usize {
+        TxSubmissionProtocol::INGRESS_LIMIT
+    }
+
     fn send_data(&mut self) -> Option<Vec<u8>> {
-        return match self.state {
+        match self.state {
             State::Idle => {
                 debug!("TxSubmissionProtocol::State::Idle");
                 None
@@ -101,15 +112,28 @@ impl Protocol for TxSubmissionProtocol {
                 self.result = Option::Some(Ok(String::from("Done")));
                 None
             }
-        };
+        }
     }
 
-    fn receive_data(&mut self, data: Vec<u8>) {
-        let cbor_value: Value = de::from_slice(&data[..]).unwrap();
+    fn terminate(&mut self) -> Option<Vec<u8>> {
+        match self.state {
+            // Done may only be sent in reply to a blocking request
+            State::TxIdsBlocking => {
+                debug!("TxSubmissionProtocol sending MsgDone");
+                self.state = State::Done;
+                self.result = Some(Ok(String::from("Done")));
+                Some(self.msg_done())
+            }
+            _ => None,
+        }
+    }
+
+    fn receive_data(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        let cbor_value: Value = de::from_slice(&data[..])?;
         match cbor_value {
             Value::Array(cbor_array) => {
-                match cbor_array[0] {
-                    Value::Integer(message_id) => {
+                match cbor_array.first() {
+                    Some(Value::Integer(message_id)) => {
                         match message_id {
                             //msgRequestTxIds = [0, tsBlocking, txCount, txCount]
                             //msgReplyTxIds   = [1, [ *txIdAndSize] ]
@@ -117,28 +141,47 @@ impl Protocol for TxSubmissionProtocol {
                             //msgReplyTxs     = [3, tsIdList ]
                             //tsMsgDone       = [4]
                             //msgReplyKTnxBye = [5]
-                            0 => {
+                            0 if matches!(self.state, State::Idle) => {
                                 debug!("TxSubmissionProtocol received MsgRequestTxIds");
-                                let is_blocking = cbor_array[1] == Value::Bool(true);
+                                let is_blocking = cbor_array.get(1) == Some(&Value::Bool(true));
                                 self.state = if is_blocking {
                                     State::TxIdsBlocking
                                 } else {
                                     State::TxIdsNonBlocking
-                                }
+                                };
+                                Ok(())
+                            }
+                            0 => {
+                                Err(Error::ProtocolViolation(format!("unexpected MsgRequestTxIds in state {:?}", self.state)))
                             }
                             _ => {
-                                error!("unexpected message_id: {}", message_id);
+                                Err(Error::ProtocolViolation(format!("unexpected message_id: {}", message_id)))
                             }
                         }
                     }
                     _ => {
-                        error!("Unexpected cbor!")
+                        Err(Error::ProtocolViolation(format!("Unexpected cbor! {}", hex::encode(&data))))
                     }
                 }
             }
             _ => {
-                error!("Unexpected cbor!")
+                Err(Error::ProtocolViolation(format!("Unexpected cbor! {}", hex::encode(&data))))
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tx_ids_are_only_requested_when_idle() {
+        /* MsgRequestTxIds, blocking, acknowledging 0 and asking for 3 */
+        let request = vec![0x84, 0x00, 0xf5, 0x00, 0x03];
+        let mut protocol = TxSubmissionProtocol::default();
+        protocol.receive_data(request.clone()).unwrap();
+        assert!(matches!(protocol.state, State::TxIdsBlocking));
+        assert!(matches!(protocol.receive_data(request), Err(Error::ProtocolViolation(_))));
+    }
+}
@@ -0,0 +1,234 @@
+/*
+© 2020 PERLUR Group
+
+SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
+
+*/
+
+use std::{
+    future::{pending, Future},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{debug, error, info, warn};
+use tokio::{
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    task::JoinSet,
+};
+
+use crate::{
+    Error,
+    Protocol,
+    mux::{
+        Subchannel,
+        tcp::{Channel, TcpBearer},
+    },
+    protocols::handshake::{DiffusionMode, HandshakeProtocol, VersionTable},
+};
+
+/* Pause after a failed accept, the cause such as a lack of file descriptors may persist for a while. */
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/* Creates a fresh protocol instance for every connection. */
+pub(crate) type Factory = Arc<dyn Fn() -> Box<dyn Protocol> + Send + Sync>;
+
+/*
+ * Accepts node-to-node connections and serves each of them on a task of its own. After the
+ * handshake every registered responder protocol runs on the connection until all of them are
+ * done or the connection fails. Initiator protocols run on the connections negotiated in
+ * duplex mode, which takes a version table offering InitiatorAndResponder.
+ */
+pub struct Server {
+    listener: TcpListener,
+    versions: VersionTable,
+    responders: Vec<Factory>,
+    initiators: Vec<Factory>,
+}
+
+impl Server {
+    /* Accept the versions of the table, or all we speak for a network magic. */
+    pub async fn bind(addr: impl ToSocketAddrs, versions: impl Into<VersionTable>) -> Result<Server, Error> {
+        Ok(Server {
+            listener: TcpListener::bind(addr).await?,
+            versions: versions.into(),
+            responders: Vec::new(),
+            initiators: Vec::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /* Run a protocol created by the factory on every connection, it must have the server role. */
+    pub fn responder<P: Protocol + 'static>(mut self, factory: impl Fn() -> P + Send + Sync + 'static) -> Self {
+        self.responders.push(Arc::new(move || Box::new(factory()) as Box<dyn Protocol>));
+        self
+    }
+
+    /*
+     * Run a protocol with the client role on every connection negotiated in duplex mode, see
+     * VersionTable::with_diffusion_mode.
+     */
+    pub fn initiator<P: Protocol + 'static>(mut self, factory: impl Fn() -> P + Send + Sync + 'static) -> Self {
+        self.initiators.push(Arc::new(move || Box::new(factory()) as Box<dyn Protocol>));
+        self
+    }
+
+    /* Accept connections for good, the returned future never resolves. */
+    pub async fn run(self) {
+        self.run_until(pending()).await
+    }
+
+    /*
+     * Accept connections until the shutdown future resolves, then close the listener. The
+     * connections being served keep running on their tasks until they are done. Failing to
+     * accept a connection, e.g. when out of file descriptors, is logged and accepting resumes
+     * after a short pause.
+     */
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) {
+        let responders: Arc<[Factory]> = self.responders.into();
+        let initiators: Arc<[Factory]> = self.initiators.into();
+        tokio::pin!(shutdown);
+        loop {
+            let accepted = tokio::select! {
+                accepted = self.listener.accept() => accepted,
+                () = &mut shutdown => {
+                    info!("server shut down");
+                    return;
+                }
+            };
+            let (stream, peer) = match accepted {
+                Ok(connection) => connection,
+                Err(error) => {
+                    error!("failed to accept a connection: {}", error);
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            info!("connection from {}", peer);
+            let responders = responders.clone();
+            let initiators = initiators.clone();
+            let versions = self.versions.clone();
+            tokio::spawn(async move {
+                match serve(stream, versions, &responders, &initiators).await {
+                    Ok(()) => info!("connection from {} closed", peer),
+                    Err(error) => error!("connection from {} failed: {}", peer, error),
+                }
+            });
+        }
+    }
+}
+
+async fn serve(stream: TcpStream, versions: VersionTable, responders: &[Factory], initiators: &[Factory]) -> Result<(), Error> {
+    let channel = Channel::new(TcpBearer::from(stream));
+
+    /* Registered before the handshake so that nothing the client sends right after it is lost. */
+    let mut subchannels = responders.iter()
+        .map(|responder| channel.register(responder()))
+        .collect::<Result<Vec<_>, Error>>()?;
+    let handshake = channel.register(HandshakeProtocol::expect(versions))?.finish().await?;
+    if handshake.negotiated().is_none() {
+        debug!("answered a version query");
+        return Ok(());
+    }
+    if handshake.diffusion_mode() == Some(DiffusionMode::InitiatorAndResponder) {
+        for initiator in initiators {
+            subchannels.push(channel.register(initiator())?);
+        }
+    }
+
+    run_all(subchannels).await
+}
+
+/* Drive protocols until all of them are done, the first error is returned. */
+pub(crate) async fn run_all(subchannels: Vec<Subchannel<Box<dyn Protocol>>>) -> Result<(), Error> {
+    let mut running = JoinSet::new();
+    for subchannel in subchannels {
+        running.spawn(subchannel.run());
+    }
+    let mut result = Ok(());
+    while let Some(finished) = running.join_next().await {
+        match finished {
+            Ok(Ok(message)) => debug!("protocol finished: {}", message),
+            Ok(Err(error)) => {
+                if result.is_ok() {
+                    result = Err(error);
+                }
+            }
+            Err(error) => warn!("protocol task failed: {}", error),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mux::tcp,
+        protocols::pingpong::PingPongProtocol,
+    };
+
+    #[tokio::test]
+    async fn connections_are_served_concurrently() {
+        let server = Server::bind("127.0.0.1:0", 764824073).await.unwrap()
+            .responder(|| PingPongProtocol::expect(0x0008));
+        let port = server.local_addr().unwrap().port();
+        tokio::spawn(server.run());
+
+        let ping = |channel: Channel| async move {
+            let pinger = channel.register(PingPongProtocol::new(0x0008))?;
+            let (pinged, closed) = tokio::join!(
+                pinger.run(),
+                async move {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    channel.close(Duration::from_secs(1)).await
+                },
+            );
+            pinged.and(closed)
+        };
+
+        /* The first connection stays open while the second one is served. */
+        let first = tcp::connect("127.0.0.1", port).await.unwrap();
+        first.handshake(764824073).await.unwrap();
+        let second = async {
+            let channel = tcp::connect("127.0.0.1", port).await?;
+            channel.handshake(764824073).await?;
+            ping(channel).await
+        };
+        tokio::time::timeout(Duration::from_secs(1), second).await
+            .expect("second connection not served while the first is open")
+            .unwrap();
+        ping(first).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_the_listener() {
+        let server = Server::bind("127.0.0.1:0", 764824073).await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(server.run_until(async { let _ = stopped.await; }));
+
+        tcp::connect("127.0.0.1", port).await.unwrap();
+        stop.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), running).await
+            .expect("server still running")
+            .unwrap();
+        assert!(tcp::connect("127.0.0.1", port).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn diffusion_mode_is_taken_from_the_versions() {
+        let duplex = || VersionTable::from(764824073).with_diffusion_mode(DiffusionMode::InitiatorAndResponder);
+        let server = Server::bind("127.0.0.1:0", duplex()).await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        tokio::spawn(server.run());
+
+        let channel = tcp::connect("127.0.0.1", port).await.unwrap();
+        let handshake = channel.handshake(duplex()).await.unwrap();
+        assert_eq!(handshake.data.diffusion_mode, DiffusionMode::InitiatorAndResponder);
+    }
+}
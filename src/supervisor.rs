@@ -0,0 +1,232 @@
+/*
+© 2020 PERLUR Group
+
+SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
+
+*/
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::{
+    Error,
+    Protocol,
+    mux::tcp,
+    protocols::handshake::{HandshakeProtocol, VersionTable},
+    server::{Factory, run_all},
+};
+
+/* How long to wait before each reconnect, the delay grows by factor after every failed attempt. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub factor: u32,
+    // Give up after this many reconnects in a row, None retries forever
+    pub retries: Option<u32>,
+    // Start over with the initial delay once a connection stayed up this long
+    pub reset_after: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            factor: 2,
+            retries: None,
+            reset_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/* Called with the number of the attempt and the error that ended the previous one. */
+type Callback = Box<dyn FnMut(u32, &Error) + Send>;
+
+/*
+ * Keeps a node-to-node connection up for long-running clients. Whenever the connection fails
+ * it is re-established and handshaken, then every protocol is created anew by its factory and
+ * run again. A ChainSyncProtocol with a store finds its intersection among the stored blocks,
+ * so syncing resumes where it stopped.
+ */
+pub struct Supervisor {
+    host: String,
+    port: u16,
+    versions: VersionTable,
+    backoff: Backoff,
+    protocols: Vec<Factory>,
+    on_reconnect: Option<Callback>,
+}
+
+impl Supervisor {
+    /* Propose the versions of the table, or all we speak for a network magic. */
+    pub fn new(host: impl Into<String>, port: u16, versions: impl Into<VersionTable>) -> Self {
+        Supervisor {
+            host: host.into(),
+            port,
+            versions: versions.into(),
+            backoff: Backoff::default(),
+            protocols: Vec::new(),
+            on_reconnect: None,
+        }
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /* Run a protocol created by the factory on every connection. */
+    pub fn protocol<P: Protocol + 'static>(mut self, factory: impl Fn() -> P + Send + Sync + 'static) -> Self {
+        self.protocols.push(Arc::new(move || Box::new(factory()) as Box<dyn Protocol>));
+        self
+    }
+
+    pub fn on_reconnect(mut self, callback: impl FnMut(u32, &Error) + Send + 'static) -> Self {
+        self.on_reconnect = Some(Box::new(callback));
+        self
+    }
+
+    /*
+     * Run the protocols until all of them are done. A refused handshake is returned right away
+     * as another attempt would be refused just the same, other errors lead to a reconnect.
+     */
+    pub async fn run(mut self) -> Result<(), Error> {
+        let mut delay = self.backoff.initial;
+        let mut attempt = 0;
+        loop {
+            let mut connected = None;
+            let error = match self.connection(&mut connected).await {
+                Ok(()) => return Ok(()),
+                Err(error @ Error::HandshakeRefused(_)) => return Err(error),
+                Err(error) => error,
+            };
+            /* A peer that hangs up right after the handshake must not be hammered with reconnects. */
+            if matches!(connected, Some(since) if since.elapsed() >= self.backoff.reset_after) {
+                attempt = 0;
+                delay = self.backoff.initial;
+            }
+            attempt += 1;
+            if matches!(self.backoff.retries, Some(retries) if attempt > retries) {
+                return Err(error);
+            }
+            warn!("connection to {}:{} failed: {}, reconnecting in {:?}", self.host, self.port, error, delay);
+            tokio::time::sleep(delay).await;
+            delay = (delay * self.backoff.factor).min(self.backoff.max);
+            if let Some(callback) = self.on_reconnect.as_mut() {
+                callback(attempt, &error);
+            }
+        }
+    }
+
+    async fn connection(&self, connected: &mut Option<Instant>) -> Result<(), Error> {
+        let channel = tcp::connect(&self.host, self.port).await?;
+        channel.execute(HandshakeProtocol::new(self.versions.clone())).await?;
+        info!("connected to {}:{}", self.host, self.port);
+        *connected = Some(Instant::now());
+
+        let subchannels = self.protocols.iter()
+            .map(|protocol| channel.register(protocol()))
+            .collect::<Result<Vec<_>, Error>>()?;
+        run_all(subchannels).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tokio::net::TcpListener;
+    use crate::{
+        Agency,
+        mux::tcp::{Channel, TcpBearer},
+        protocols::chainsync::ChainSyncProtocol,
+        server::Server,
+        testing::Scripted,
+    };
+
+    #[tokio::test]
+    async fn failed_connections_are_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            /* Hang up on the first two connections. */
+            for _ in 0..2 {
+                drop(listener.accept().await.unwrap());
+            }
+            let (stream, _) = listener.accept().await.unwrap();
+            let channel = Channel::new(TcpBearer::from(stream));
+            let responder = channel.register(Scripted::responder(0x0008, vec![vec![0x80]])).unwrap();
+            channel.execute(HandshakeProtocol::expect(764824073)).await.unwrap();
+            responder.run().await.unwrap();
+        });
+
+        let reconnects = Arc::new(AtomicU32::new(0));
+        let counter = reconnects.clone();
+        Supervisor::new("127.0.0.1", port, 764824073)
+            .backoff(Backoff {
+                initial: Duration::from_millis(1),
+                max: Duration::from_millis(2),
+                factor: 2,
+                retries: Some(5),
+                reset_after: Duration::from_secs(60),
+            })
+            /* A single request and response, which ends the run once it gets through. */
+            .protocol(|| Scripted::new(0x0008, Agency::Client, vec![vec![0x80]]))
+            .on_reconnect(move |attempt, _| counter.store(attempt, Ordering::SeqCst))
+            .run().await.unwrap();
+        assert_eq!(reconnects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn retries_are_limited() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                drop(listener.accept().await.unwrap());
+            }
+        });
+
+        let result = Supervisor::new("127.0.0.1", port, 764824073)
+            .backoff(Backoff {
+                initial: Duration::from_millis(1),
+                max: Duration::from_millis(2),
+                factor: 2,
+                retries: Some(2),
+                reset_after: Duration::from_secs(60),
+            })
+            .protocol(ChainSyncProtocol::default)
+            .run().await;
+        assert!(matches!(result, Err(Error::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn short_lived_connections_do_not_reset_the_backoff() {
+        /* Handshakes and hangs up as no responders are registered. */
+        let server = Server::bind("127.0.0.1:0", 764824073).await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        tokio::spawn(server.run());
+
+        let reconnects = Arc::new(AtomicU32::new(0));
+        let counter = reconnects.clone();
+        let supervisor = Supervisor::new("127.0.0.1", port, 764824073)
+            .backoff(Backoff {
+                initial: Duration::from_millis(1),
+                max: Duration::from_millis(2),
+                factor: 2,
+                retries: Some(2),
+                reset_after: Duration::from_secs(60),
+            })
+            .protocol(ChainSyncProtocol::default)
+            .on_reconnect(move |attempt, _| counter.store(attempt, Ordering::SeqCst));
+        let result = tokio::time::timeout(Duration::from_secs(5), supervisor.run()).await
+            .expect("reconnects were not limited");
+        assert!(matches!(result, Err(Error::Disconnected)));
+        assert_eq!(reconnects.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,101 @@
+/*
+© 2020 PERLUR Group
+
+SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
+
+*/
+
+use std::{
+    collections::VecDeque,
+    time::Duration,
+};
+
+use crate::{Agency, Error, Protocol};
+
+/*
+ * Plays a scripted part in a mini protocol for the tests. The client speaks first, each side
+ * sends the next message of its script whenever it has agency and takes whatever the peer
+ * sends. The exchange ends once the client has nothing more to say, the server stops right
+ * after its last message unless the client has the last word.
+ */
+pub(crate) struct Scripted {
+    pub(crate) id: u16,
+    pub(crate) role: Agency,
+    pub(crate) agency: Agency,
+    pub(crate) script: VecDeque<Vec<u8>>,
+    // Size of the last message received
+    pub(crate) received: usize,
+    pub(crate) timeout: Option<Duration>,
+    // The server takes one more message from the client after its last one
+    pub(crate) client_ends: bool,
+}
+
+impl Scripted {
+    pub(crate) fn new(id: u16, role: Agency, script: impl IntoIterator<Item = Vec<u8>>) -> Self {
+        Scripted {
+            id,
+            role,
+            agency: Agency::Client,
+            script: script.into_iter().collect(),
+            received: 0,
+            timeout: None,
+            client_ends: false,
+        }
+    }
+
+    /* Answers every message of the client with the next reply. */
+    pub(crate) fn responder(id: u16, replies: impl IntoIterator<Item = Vec<u8>>) -> Self {
+        Scripted::new(id, Agency::Server, replies)
+    }
+
+    fn peer(&self) -> Agency {
+        match self.role {
+            Agency::Client => Agency::Server,
+            _ => Agency::Client,
+        }
+    }
+}
+
+impl Protocol for Scripted {
+    fn protocol_id(&self) -> u16 {
+        match self.role {
+            Agency::Server => self.id ^ 0x8000,
+            _ => self.id,
+        }
+    }
+
+    fn result(&self) -> Result<String, Error> {
+        Ok(format!("{:04x} received {}", self.id, self.received))
+    }
+
+    fn role(&self) -> Agency {
+        self.role
+    }
+
+    fn agency(&self) -> Agency {
+        self.agency
+    }
+
+    fn state(&self) -> String {
+        format!("{:?}", self.agency)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn send_data(&mut self) -> Option<Vec<u8>> {
+        let message = self.script.pop_front()?;
+        self.agency = match self.role {
+            Agency::Server if self.script.is_empty() && !self.client_ends => Agency::None,
+            _ => self.peer(),
+        };
+        Some(message)
+    }
+
+    fn receive_data(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        self.received = data.len();
+        self.agency = if self.script.is_empty() { Agency::None } else { self.role };
+        Ok(())
+    }
+}